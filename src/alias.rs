@@ -0,0 +1,158 @@
+/* alias.rs
+    Put a talker alias back together. The radio sends a header block
+    with the format and length, then as many of the three following
+    blocks as the text needs. Blocks can arrive in any order and more
+    than once, we decode as soon as we have enough of them.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    SevenBit,
+    Iso8,
+    Utf8,
+    Utf16,
+}
+
+#[derive(Default)]
+pub struct Alias {
+    blocks: [Option<[u8; 7]>; 4],
+    pub text: Option<String>,
+}
+
+impl Alias {
+    /* Add a block, returns true when this block completed the alias */
+    pub fn add(&mut self, block: u8, data: &[u8; 7]) -> bool {
+        let i = block as usize;
+        if i >= self.blocks.len() || self.text.is_some() {
+            return false;
+        }
+        self.blocks[i] = Some(*data);
+        self.text = self.decode();
+        self.text.is_some()
+    }
+
+    pub fn format(&self) -> Option<Format> {
+        self.blocks[0].map(|h| match h[0] >> 6 {
+            0 => Format::SevenBit,
+            1 => Format::Iso8,
+            2 => Format::Utf8,
+            _ => Format::Utf16,
+        })
+    }
+
+    // Characters (or bytes for UTF-8) the radio said it's sending
+    pub fn length(&self) -> Option<usize> {
+        self.blocks[0].map(|h| ((h[0] >> 1) & 0x1f) as usize)
+    }
+
+    fn decode(&self) -> Option<String> {
+        let format = self.format()?;
+        // Each format starts its text at a different bit of the header
+        let (width, offset) = match format {
+            Format::SevenBit => (7, 7),
+            Format::Utf16 => (16, 8),
+            _ => (8, 8),
+        };
+        // A length past what the four blocks can hold gets what they do hold
+        let len = self.length()?.min((4 * 56 - offset) / width);
+        // Header text then each block in turn, all must be here to decode
+        let needed = (offset + len * width).div_ceil(56);
+        let mut raw = Vec::with_capacity(needed * 7);
+        for b in &self.blocks[..needed] {
+            raw.extend_from_slice(b.as_ref()?);
+        }
+        let bit = |n: usize| raw.get(n / 8).map(|b| (b >> (7 - n % 8)) & 1);
+
+        let text = match format {
+            Format::SevenBit => (0..len)
+                .map(|c| (0..7).try_fold(0u8, |a, i| Some(a << 1 | bit(offset + c * 7 + i)?)))
+                .map(|c| c.map(char::from))
+                .collect::<Option<String>>()?,
+            Format::Iso8 => raw[1..].iter().take(len).map(|&b| char::from(b)).collect(),
+            Format::Utf8 => String::from_utf8_lossy(raw[1..].get(..len)?).into_owned(),
+            Format::Utf16 => {
+                let units: Vec<u16> = raw[1..]
+                    .chunks_exact(2)
+                    .take(len)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16_lossy(&units)
+            }
+        };
+        Some(text.trim_end_matches(['\0', ' ']).to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Header byte then text, cut into the 7 byte blocks a radio sends
+    fn blocks(raw: &[u8]) -> Vec<[u8; 7]> {
+        raw.chunks(7)
+            .map(|c| {
+                let mut b = [0; 7];
+                b[..c.len()].copy_from_slice(c);
+                b
+            })
+            .collect()
+    }
+
+    fn header(format: u8, len: usize) -> u8 {
+        format << 6 | (len as u8) << 1
+    }
+
+    // Feed the blocks in the order given, the alias should complete on the last
+    fn decode(blocks: &[[u8; 7]], order: &[usize]) -> String {
+        let mut alias = Alias::default();
+        for (n, &i) in order.iter().enumerate() {
+            let done = alias.add(i as u8, &blocks[i]);
+            assert_eq!(done, n == order.len() - 1, "block {}", i);
+        }
+        alias.text.unwrap()
+    }
+
+    #[test]
+    fn seven_bit() {
+        // Format and length take 7 bits, the text carries on from the 8th
+        let text = "M0ABC/MM";
+        let mut bits: Vec<u8> = (0..7).rev().map(|i| header(0, 8) >> (i + 1) & 1).collect();
+        for c in text.bytes() {
+            bits.extend((0..7).rev().map(|i| c >> i & 1));
+        }
+        let raw: Vec<u8> = bits
+            .chunks(8)
+            .map(|c| c.iter().enumerate().fold(0, |a, (i, b)| a | b << (7 - i)))
+            .collect();
+        assert_eq!(decode(&blocks(&raw), &[1, 0]), text);
+    }
+
+    #[test]
+    fn iso8() {
+        let raw = [&[header(1, 4)], b"\xc5nne".as_slice()].concat();
+        assert_eq!(decode(&blocks(&raw), &[0]), "\u{c5}nne");
+    }
+
+    #[test]
+    fn utf8() {
+        let text = "Zo\u{eb} \u{263a} M0ABC";
+        let raw = [&[header(2, text.len())], text.as_bytes()].concat();
+        assert_eq!(decode(&blocks(&raw), &[2, 0, 1]), text);
+    }
+
+    #[test]
+    fn utf16() {
+        let text = "\u{3a9}mega";
+        let units: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        let raw = [&[header(3, 5)], units.as_slice()].concat();
+        assert_eq!(decode(&blocks(&raw), &[0, 1]), text);
+    }
+
+    // 31 bytes claimed, four blocks only carry 27
+    #[test]
+    fn length_past_the_blocks() {
+        let text = "ABCDEFGHIJKLMNOPQRSTUVWXYZ0";
+        let raw = [&[header(2, 31)], text.as_bytes()].concat();
+        assert_eq!(decode(&blocks(&raw), &[0, 1, 3, 2]), text);
+    }
+}
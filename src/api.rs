@@ -0,0 +1,446 @@
+use crate::{
+    db,
+    directory::Directory,
+    events::{Events, Filter},
+    heard::{Call, CallType, LastHeard},
+    master::Master,
+    metrics::Metrics,
+    peers::Peer,
+    rules::Scope,
+    streams::Streams,
+    system::System,
+};
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/* api.rs
+    A read only JSON view of the server over HTTP. The main loop never
+    waits on a client: it builds a snapshot every second (and whenever a
+    call ends) and the server thread answers from the latest one.
+
+    GET /api/status       everything below except last heard
+    GET /api/peers        connected repeaters, /api/peers/<id> for one
+    GET /api/masters      upstream masters
+    GET /api/streams      calls in progress
+    GET /api/heard        last heard, newest first, ?radio= ?tg= ?peer= ?limit=
+    GET /api/stats        counters
+    GET /api/events       live events as server-sent events, see events.rs
+    GET /metrics          the same counters and more for Prometheus
+*/
+
+// Where the API listens, off unless an address is given
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub bind: String,
+}
+
+// Most last-heard records one request gets unless it asks for fewer
+const HEARD_LIMIT: usize = 100;
+
+pub struct Api {
+    snapshot: Arc<Mutex<Snapshot>>,
+}
+
+#[derive(Default)]
+struct Snapshot {
+    peers: Vec<PeerStatus>,
+    masters: Vec<MasterStatus>,
+    streams: Vec<StreamStatus>,
+    stats: Stats,
+    heard: Option<LastHeard>,
+    metrics: String,
+}
+
+#[derive(Serialize)]
+pub struct PeerStatus {
+    pub id: u32,
+    pub callsign: String,
+    pub state: String,
+    pub ip: String,
+    pub location: String,
+    pub description: String,
+    pub url: String,
+    pub rx_freq: u32,
+    pub tx_freq: u32,
+    pub color_code: u8,
+    pub power: u16,
+    pub latitude: f32,
+    pub longitude: f32,
+    pub height: u16,
+    pub duplex: u8,
+    pub software: String,
+    pub package: String,
+    pub options: String,
+    pub rx_bytes: usize,
+    pub tx_bytes: usize,
+    pub last_ping: u64,
+    pub status: Option<String>,
+    // How often the repeater has asked us for a beacon
+    pub beacon_requests: usize,
+    pub talkgroups: Vec<TgStatus>,
+    pub slots: Vec<SlotStatus>,
+}
+
+#[derive(Serialize)]
+pub struct TgStatus {
+    pub id: u32,
+    pub name: Option<String>,
+    pub slot: u8,
+    // User activated, and the minutes it lasts without traffic
+    pub ua: bool,
+    pub expire: u64,
+    pub last_activity: u64,
+}
+
+// A slot locked to a talkgroup
+#[derive(Serialize)]
+pub struct SlotStatus {
+    pub slot: u8,
+    pub tg: u32,
+    pub since: u64,
+}
+
+#[derive(Serialize)]
+pub struct MasterStatus {
+    pub name: String,
+    pub id: u32,
+    pub address: String,
+    pub state: String,
+    pub rx_bytes: usize,
+    pub tx_bytes: usize,
+    pub reconnects: usize,
+    pub failures: usize,
+    pub naks: usize,
+    pub closes: usize,
+    pub last_failure: Option<String>,
+    pub retry: u64,
+}
+
+#[derive(Serialize)]
+pub struct StreamStatus {
+    pub id: u32,
+    pub peer: u32,
+    pub src: u32,
+    pub callsign: Option<String>,
+    pub dst: u32,
+    pub dst_name: Option<String>,
+    pub slot: u8,
+    pub start: u64,
+    pub frames: u32,
+    pub lost: u32,
+    pub alias: Option<String>,
+    pub blocked: bool,
+    pub receivers: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct Stats {
+    pub uptime: u64,
+    pub peers: usize,
+    pub masters_connected: usize,
+    pub streams_active: usize,
+    pub streams_total: usize,
+    pub directory_users: usize,
+    pub directory_talkgroups: usize,
+    pub acl_denied: usize,
+    pub master_reconnects: usize,
+    pub master_failures: usize,
+    pub master_naks: usize,
+    pub master_closes: usize,
+    pub peer_closes: usize,
+    pub streams_blocked: usize,
+    pub total_timeouts: usize,
+}
+
+#[derive(Serialize)]
+struct Status<'a> {
+    peers: &'a [PeerStatus],
+    masters: &'a [MasterStatus],
+    streams: &'a [StreamStatus],
+    stats: &'a Stats,
+}
+
+impl Api {
+    // Start answering requests on `bind`
+    pub fn start(bind: &str, events: Events) -> Result<Self, String> {
+        let server = tiny_http::Server::http(bind).map_err(|e| e.to_string())?;
+        let snapshot = Arc::new(Mutex::new(Snapshot::default()));
+        let shared = snapshot.clone();
+        thread::Builder::new()
+            .name("api".to_owned())
+            .spawn(move || {
+                for request in server.incoming_requests() {
+                    let (path, query) = split(request.url());
+                    if path == "/api/events" && *request.method() == tiny_http::Method::Get {
+                        match Filter::parse(&query) {
+                            Ok(f) => events.subscribe(f, request.into_writer()),
+                            Err(e) => reply(request, 400, error(&e), "application/json"),
+                        }
+                        continue;
+                    }
+                    let metrics = request.url() == "/metrics";
+                    let (code, body) = match shared.lock() {
+                        Ok(s) if metrics => (200, s.metrics.clone()),
+                        Ok(s) => answer(&s, request.method(), request.url()),
+                        Err(_) => (500, error("unavailable")),
+                    };
+                    let kind = match metrics {
+                        true => "text/plain; version=0.0.4",
+                        false => "application/json",
+                    };
+                    reply(request, code, body, kind);
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(Self { snapshot })
+    }
+
+    // Take a fresh copy of everything but last heard
+    pub fn update(
+        &self,
+        mash: &HashMap<u32, Peer>,
+        masters: &[Master],
+        streams: &Streams,
+        system: &System,
+        directory: &Directory,
+        metrics: &Metrics,
+    ) {
+        let rendered = metrics.render(mash, masters, streams, system);
+        let mut peers: Vec<PeerStatus> = mash.values().map(|p| peer(p, directory)).collect();
+        peers.sort_by_key(|p| p.id);
+        let mut active: Vec<StreamStatus> = streams
+            .current_streams
+            .values()
+            .map(|s| StreamStatus {
+                id: s.id,
+                peer: s.peer,
+                src: s.src,
+                callsign: directory.user(s.src).map(|u| u.callsign.clone()),
+                dst: s.dst,
+                dst_name: directory.talkgroup(s.dst).map(|n| n.to_owned()),
+                slot: s.slot,
+                start: db::unix(s.start_time),
+                frames: s.frames,
+                lost: s.lost,
+                alias: s.alias.text.clone(),
+                blocked: s.blocked,
+                receivers: s.receivers.iter().map(|r| scope(*r, masters)).collect(),
+            })
+            .collect();
+        active.sort_by_key(|s| s.start);
+        let stats = stats(mash, masters, streams, system, directory);
+        let masters = masters.iter().map(master).collect();
+        if let Ok(mut s) = self.snapshot.lock() {
+            s.peers = peers;
+            s.masters = masters;
+            s.streams = active;
+            s.stats = stats;
+            s.metrics = rendered;
+        }
+    }
+
+    // Last heard only changes when a call ends
+    pub fn heard(&self, last_heard: &LastHeard) {
+        if let Ok(mut s) = self.snapshot.lock() {
+            s.heard = Some(last_heard.clone());
+        }
+    }
+}
+
+pub fn peer(p: &Peer, directory: &Directory) -> PeerStatus {
+    let mut talkgroups: Vec<TgStatus> = p
+        .talk_groups
+        .values()
+        .map(|t| TgStatus {
+            id: t.id,
+            name: directory.talkgroup(t.id).map(|n| n.to_owned()),
+            slot: t.sl,
+            ua: t.ua,
+            expire: t.expire,
+            last_activity: db::unix(t.la),
+        })
+        .collect();
+    talkgroups.sort_by_key(|t| t.id);
+    PeerStatus {
+        id: p.id,
+        callsign: p.callsign.clone(),
+        state: format!("{:?}", p.state),
+        ip: p.ip.to_string(),
+        location: p.location.clone(),
+        description: p.description.clone(),
+        url: p.url.clone(),
+        rx_freq: p.rx_freq,
+        tx_freq: p.tx_freq,
+        color_code: p.color_code,
+        power: p.power,
+        latitude: p.latitude,
+        longitude: p.longitude,
+        height: p.height,
+        duplex: p.duplex,
+        software: p.software.clone(),
+        package: p.package.clone(),
+        options: p.options.clone(),
+        rx_bytes: p.rx_bytes,
+        tx_bytes: p.tx_bytes,
+        last_ping: db::unix(p.last_check),
+        status: p.status.as_ref().map(|s| s.text()),
+        beacon_requests: p.beacon_requests,
+        talkgroups,
+        slots: [1, 2]
+            .into_iter()
+            .filter_map(|sl| {
+                p.slot.locked(sl).map(|(tg, t)| SlotStatus {
+                    slot: sl,
+                    tg,
+                    since: db::unix(t),
+                })
+            })
+            .collect(),
+    }
+}
+
+// The counters, for /api/stats and dmrpalctl
+pub fn stats(
+    mash: &HashMap<u32, Peer>,
+    masters: &[Master],
+    streams: &Streams,
+    system: &System,
+    directory: &Directory,
+) -> Stats {
+    Stats {
+        uptime: system.uptime.elapsed().unwrap_or_default().as_secs(),
+        peers: mash.len(),
+        masters_connected: masters.iter().filter(|m| m.connected()).count(),
+        streams_active: streams.current_streams.len(),
+        streams_total: streams.total,
+        directory_users: directory.users(),
+        directory_talkgroups: directory.talkgroups(),
+        acl_denied: system.acl_denied,
+        master_reconnects: system.master_reconnects,
+        master_failures: system.master_failures,
+        master_naks: system.master_naks,
+        master_closes: system.master_closes,
+        peer_closes: system.peer_closes,
+        streams_blocked: system.streams_blocked,
+        total_timeouts: system.total_timeouts,
+    }
+}
+
+pub fn master(m: &Master) -> MasterStatus {
+    MasterStatus {
+        name: m.name.clone(),
+        id: m.peer.id,
+        address: m.peer.ip.to_string(),
+        state: format!("{:?}", m.state),
+        rx_bytes: m.peer.rx_bytes,
+        tx_bytes: m.peer.tx_bytes,
+        reconnects: m.reconnects,
+        failures: m.failures,
+        naks: m.naks,
+        closes: m.closes,
+        last_failure: m.last_failure.map(|f| f.to_string()),
+        retry: m.retry.as_secs(),
+    }
+}
+
+fn scope(s: Scope, masters: &[Master]) -> String {
+    match s {
+        Scope::Peer(id) => id.to_string(),
+        Scope::Master(i) => masters
+            .get(i)
+            .map_or_else(|| s.to_string(), |m| m.name.clone()),
+    }
+}
+
+fn answer(s: &Snapshot, method: &tiny_http::Method, url: &str) -> (u16, String) {
+    if *method != tiny_http::Method::Get {
+        return (405, error("method not allowed"));
+    }
+    let (path, query) = split(url);
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match parts[..] {
+        ["api", "status"] => ok(&Status {
+            peers: &s.peers,
+            masters: &s.masters,
+            streams: &s.streams,
+            stats: &s.stats,
+        }),
+        ["api", "peers"] => ok(&s.peers),
+        ["api", "peers", id] => match s.peers.iter().find(|p| id.parse() == Ok(p.id)) {
+            Some(p) => ok(p),
+            None => (404, error("no such peer")),
+        },
+        ["api", "masters"] => ok(&s.masters),
+        ["api", "streams"] => ok(&s.streams),
+        ["api", "stats"] => ok(&s.stats),
+        ["api", "heard"] => heard(s.heard.as_ref(), &query),
+        _ => (404, error("not found")),
+    }
+}
+
+fn reply(request: tiny_http::Request, code: u16, body: String, kind: &str) {
+    let header = tiny_http::Header::from_bytes("Content-Type", kind).expect("valid header");
+    let response = tiny_http::Response::from_string(body)
+        .with_status_code(code)
+        .with_header(header);
+    // The client may have gone, nothing to do about it
+    let _ = request.respond(response);
+}
+
+// The path and the query parameters
+fn split(url: &str) -> (&str, HashMap<&str, &str>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let query = query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .collect();
+    (path, query)
+}
+
+fn heard(last_heard: Option<&LastHeard>, query: &HashMap<&str, &str>) -> (u16, String) {
+    let number = |k| query.get(k).map(|v| v.parse::<u32>());
+    let limit = match query.get("limit").map(|v| v.parse::<usize>()) {
+        Some(Ok(n)) => n,
+        Some(Err(_)) => return (400, error("limit is not a number")),
+        None => HEARD_LIMIT,
+    };
+    let Some(last_heard) = last_heard else {
+        return ok(&Vec::<Call>::new());
+    };
+    let (radio, tg, peer) = match (number("radio"), number("tg"), number("peer")) {
+        (Some(Err(_)), _, _) | (_, Some(Err(_)), _) | (_, _, Some(Err(_))) => {
+            return (400, error("radio, tg and peer must be numbers"))
+        }
+        (r, t, p) => (
+            r.map(Result::unwrap),
+            t.map(Result::unwrap),
+            p.map(Result::unwrap),
+        ),
+    };
+    // Every filter given has to match
+    let calls: Vec<&Call> = last_heard
+        .recent()
+        .filter(|c| radio.is_none_or(|r| c.src == r))
+        .filter(|c| tg.is_none_or(|t| c.call_type == CallType::Group && c.dst == t))
+        .filter(|c| peer.is_none_or(|p| c.peer == p))
+        .take(limit)
+        .collect();
+    ok(&calls)
+}
+
+fn ok<T: Serialize + ?Sized>(value: &T) -> (u16, String) {
+    match serde_json::to_string(value) {
+        Ok(body) => (200, body),
+        Err(e) => (500, error(&e.to_string())),
+    }
+}
+
+fn error(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
@@ -0,0 +1,295 @@
+use dmrpal::{
+    control::{Command, Reply},
+    system::{Config, ConfigError},
+};
+use serde_json::Value;
+use std::{
+    env::args,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    process::exit,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/* dmrpalctl
+    Talks to a running dmrpal over its control socket, see control.rs.
+    The socket comes from [control] in the config unless --socket is given.
+*/
+
+const DEFAULT_CONFIG: &str = "dmrpal.toml";
+
+fn usage() {
+    println!(
+        "Usage: dmrpalctl [--config <path>] [--socket <path>] [--json] <command>
+
+Commands:
+  peers                         repeaters logged in
+  masters                       upstream masters
+  peer <id>                     a repeater with its talkgroups and slot locks
+  kick <id>                     log a repeater out, it can log in again
+  block <id>                    log a repeater out and refuse it until unblocked
+  unblock <id>
+  blocked                       repeaters refused by block
+  add-tg <id> <tg> <slot> [ua]  give a repeater a static or user activated talkgroup
+  remove-tg <id> <tg>
+  clear-ua <id>                 drop a repeater's user activated talkgroups
+  reload                        read the config and directory again
+  stats                         counters
+  seen                          every repeater that has logged in, from the database"
+    );
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("dmrpalctl: {}", msg);
+    exit(1);
+}
+
+fn number<T: std::str::FromStr>(arg: Option<&String>, what: &str) -> T {
+    match arg.map(|a| a.parse()) {
+        Some(Ok(n)) => n,
+        Some(Err(_)) => fail(&format!("{} must be a number", what)),
+        None => {
+            usage();
+            exit(2);
+        }
+    }
+}
+
+fn command(words: &[String]) -> Command {
+    let id = || number(words.get(1), "id");
+    match words.first().map(String::as_str) {
+        Some("peers") => Command::Peers,
+        Some("masters") => Command::Masters,
+        Some("peer") => Command::Peer { id: id() },
+        Some("kick") => Command::Kick { id: id() },
+        Some("block") => Command::Block { id: id() },
+        Some("unblock") => Command::Unblock { id: id() },
+        Some("blocked") => Command::Blocked,
+        Some("add-tg") => Command::AddTg {
+            id: id(),
+            tg: number(words.get(2), "talkgroup"),
+            slot: number(words.get(3), "slot"),
+            ua: match words.get(4).map(String::as_str) {
+                None | Some("static") => false,
+                Some("ua") => true,
+                Some(w) => fail(&format!("expected ua or static, not {}", w)),
+            },
+        },
+        Some("remove-tg") => Command::RemoveTg {
+            id: id(),
+            tg: number(words.get(2), "talkgroup"),
+        },
+        Some("clear-ua") => Command::ClearUa { id: id() },
+        Some("reload") => Command::Reload,
+        Some("stats") => Command::Stats,
+        Some("seen") => Command::Seen,
+        _ => {
+            usage();
+            exit(2);
+        }
+    }
+}
+
+// Send one command and wait for its reply
+fn send(socket: &str, command: &Command) -> Result<Reply, String> {
+    let conn = UnixStream::connect(socket).map_err(|e| format!("{}: {}", socket, e))?;
+    let _ = conn.set_read_timeout(Some(Duration::from_secs(15)));
+    let json = serde_json::to_string(command).map_err(|e| e.to_string())?;
+    writeln!(&conn, "{}", json).map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    BufReader::new(&conn)
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())?;
+    serde_json::from_str(&line).map_err(|e| format!("bad reply: {}", e))
+}
+
+// A JSON value as plain text, strings without their quotes
+fn text(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".to_owned(),
+        v => v.to_string(),
+    }
+}
+
+// Seconds since the epoch as time ago, "3d 4h" or "12m"
+fn ago(v: &Value) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let s = match v.as_u64() {
+        Some(t) => now.saturating_sub(t),
+        None => return "-".to_owned(),
+    };
+    match s {
+        0..=59 => format!("{}s", s),
+        60..=3599 => format!("{}m", s / 60),
+        3600..=86399 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        _ => format!("{}d {}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+fn show(command: &Command, data: &Value) {
+    let rows = data.as_array().map(Vec::as_slice).unwrap_or_default();
+    match command {
+        Command::Peers => {
+            println!(
+                "{:<10} {:<10} {:<13} {:<22} TALKGROUPS",
+                "ID", "CALLSIGN", "STATE", "ADDRESS"
+            );
+            for p in rows {
+                let tgs = p["talkgroups"].as_array().map_or(0, |t| {
+                    t.iter().filter(|t| t["id"].as_u64() != Some(0)).count()
+                });
+                println!(
+                    "{:<10} {:<10} {:<13} {:<22} {}",
+                    text(&p["id"]),
+                    text(&p["callsign"]),
+                    text(&p["state"]),
+                    text(&p["ip"]),
+                    tgs
+                );
+            }
+        }
+        Command::Masters => {
+            println!(
+                "{:<16} {:<13} {:<22} {:<10} LAST FAILURE",
+                "NAME", "STATE", "ADDRESS", "RECONNECTS"
+            );
+            for m in rows {
+                println!(
+                    "{:<16} {:<13} {:<22} {:<10} {}",
+                    text(&m["name"]),
+                    text(&m["state"]),
+                    text(&m["address"]),
+                    text(&m["reconnects"]),
+                    text(&m["last_failure"])
+                );
+            }
+        }
+        Command::Peer { .. } => {
+            for k in [
+                "id",
+                "callsign",
+                "state",
+                "ip",
+                "location",
+                "software",
+                "rx_freq",
+                "tx_freq",
+                "color_code",
+                "duplex",
+                "options",
+                "status",
+                "beacon_requests",
+            ] {
+                println!("{:<16} {}", k, text(&data[k]));
+            }
+            println!("\n{:<10} {:<5} {:<7} NAME", "TALKGROUP", "SLOT", "TYPE");
+            for t in data["talkgroups"].as_array().into_iter().flatten() {
+                if t["id"].as_u64() == Some(0) {
+                    continue;
+                }
+                let kind = match t["ua"].as_bool() {
+                    Some(true) => "ua",
+                    _ => "static",
+                };
+                println!(
+                    "{:<10} {:<5} {:<7} {}",
+                    text(&t["id"]),
+                    text(&t["slot"]),
+                    kind,
+                    text(&t["name"])
+                );
+            }
+            let slots = data["slots"]
+                .as_array()
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            println!();
+            if slots.is_empty() {
+                println!("No slots locked");
+            }
+            for s in slots {
+                println!("TS{} locked to {}", text(&s["slot"]), text(&s["tg"]));
+            }
+        }
+        Command::Blocked if rows.is_empty() => println!("No peers blocked"),
+        Command::Blocked => {
+            for id in rows {
+                println!("{}", id);
+            }
+        }
+        Command::Seen => {
+            println!(
+                "{:<10} {:<10} {:<22} {:<10} {:<10} LOCATION",
+                "ID", "CALLSIGN", "ADDRESS", "FIRST", "LAST"
+            );
+            for p in rows {
+                println!(
+                    "{:<10} {:<10} {:<22} {:<10} {:<10} {}",
+                    text(&p["id"]),
+                    text(&p["callsign"]),
+                    text(&p["ip"]),
+                    ago(&p["first_seen"]),
+                    ago(&p["last_seen"]),
+                    text(&p["location"])
+                );
+            }
+        }
+        Command::Stats => {
+            for (k, v) in data.as_object().into_iter().flatten() {
+                println!("{:<22} {}", k, text(v));
+            }
+        }
+        _ => println!("{}", text(data)),
+    }
+}
+
+fn main() {
+    let arg: Vec<String> = args().collect();
+    let mut config_path = None;
+    let mut socket = None;
+    let mut json = false;
+    let mut i = 1;
+    while i < arg.len() {
+        match (arg[i].as_ref(), arg.get(i + 1)) {
+            ("--config" | "-c", Some(path)) => config_path = Some(path.to_owned()),
+            ("--socket" | "-s", Some(path)) => socket = Some(path.to_owned()),
+            ("--json" | "-j", _) => {
+                json = true;
+                i += 1;
+                continue;
+            }
+            ("--help" | "-h", _) => {
+                usage();
+                return;
+            }
+            _ => break,
+        }
+        i += 2;
+    }
+    let command = command(&arg[i..]);
+
+    let socket = socket.unwrap_or_else(|| {
+        let path = config_path.as_deref().unwrap_or(DEFAULT_CONFIG);
+        match Config::load(path) {
+            Ok(c) => c.control.socket,
+            Err(ConfigError::Read(..)) if config_path.is_none() => fail(&format!(
+                "no {} here, give --config or --socket",
+                DEFAULT_CONFIG
+            )),
+            Err(e) => fail(&e.to_string()),
+        }
+    });
+    if socket.is_empty() {
+        fail("the control socket is off, set socket in [control]");
+    }
+
+    match send(&socket, &command) {
+        Ok(Reply::Ok(data)) if json => println!("{:#}", data),
+        Ok(Reply::Ok(data)) => show(&command, &data),
+        Ok(Reply::Error(e)) => fail(&e),
+        Err(e) => fail(&e),
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{notice, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, UdpSocket},
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+/* control.rs
+    Local administration over a Unix socket, used by dmrpalctl. A client
+    sends one JSON command on a line and gets one JSON reply on a line:
+
+        {"cmd":"kick","id":2345601}
+        {"ok":"Peer 2345601 kicked"}
+
+    Commands are handed to the main loop and run between packets. An empty
+    datagram to the DMR socket wakes the loop so it doesn't wait for the
+    next timer. Only the user dmrpal runs as can use the socket.
+*/
+
+// How long a client gets to send its command, and how long we wait for the main loop
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where the control socket is made, off unless a path is given
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    pub socket: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Command {
+    Peers,
+    Masters,
+    // One peer with its talkgroups and slot locks
+    Peer {
+        id: u32,
+    },
+    Kick {
+        id: u32,
+    },
+    // Kick and refuse logins until unblocked or restarted
+    Block {
+        id: u32,
+    },
+    Unblock {
+        id: u32,
+    },
+    Blocked,
+    // Static, or user activated with the peer's usual expiry when `ua` is set
+    AddTg {
+        id: u32,
+        tg: u32,
+        slot: u8,
+        ua: bool,
+    },
+    RemoveTg {
+        id: u32,
+        tg: u32,
+    },
+    ClearUa {
+        id: u32,
+    },
+    Reload,
+    Stats,
+    // Every repeater the database has seen log in
+    Seen,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Reply {
+    Ok(serde_json::Value),
+    Error(String),
+}
+
+// A command waiting for the main loop
+pub struct Request {
+    pub command: Command,
+    reply: Sender<Reply>,
+}
+
+pub struct Control {
+    requests: Receiver<Request>,
+    path: String,
+}
+
+impl Request {
+    pub fn answer(self, reply: Reply) {
+        let _ = self.reply.send(reply);
+    }
+}
+
+impl Reply {
+    // A plain message for commands that only do something
+    pub fn done(msg: String) -> Self {
+        Reply::Ok(msg.into())
+    }
+
+    pub fn data(data: impl serde::Serialize) -> Self {
+        match serde_json::to_value(data) {
+            Ok(v) => Reply::Ok(v),
+            Err(e) => Reply::Error(e.to_string()),
+        }
+    }
+}
+
+/* The socket takes its mode from the umask, so it is made in a directory only
+we can get into and only moved to `path` once it is 0600. */
+fn bind(path: &str) -> io::Result<UnixListener> {
+    let dir = format!("{}.new", path);
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = Path::new(&dir).join("sock");
+    let bound = UnixListener::bind(&tmp).and_then(|l| {
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&tmp, path)?;
+        Ok(l)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    bound
+}
+
+impl Control {
+    /* Listen on `path`, taking over a socket left behind by a dmrpal that
+    didn't exit cleanly. `wake` is where the main loop is listening. */
+    pub fn start(path: &str, wake: SocketAddr) -> Result<Self, String> {
+        if Path::new(path).exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(format!("{} is in use, is dmrpal already running?", path));
+            }
+            fs::remove_file(path).map_err(|e| format!("unable to remove {}: {}", path, e))?;
+        }
+        let listener = bind(path).map_err(|e| format!("{}: {}", path, e))?;
+        let (tx, requests) = mpsc::channel();
+        thread::Builder::new()
+            .name("control".to_owned())
+            .spawn(move || {
+                for conn in listener.incoming() {
+                    match conn {
+                        Ok(c) => client(c, &tx, wake),
+                        Err(e) => warn!("Control socket: {}", e),
+                    }
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            requests,
+            path: path.to_owned(),
+        })
+    }
+
+    // Commands that have arrived since last time
+    pub fn pending(&self) -> Vec<Request> {
+        self.requests.try_iter().collect()
+    }
+
+    // Tidy up on the way out
+    pub fn close(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Send the main loop an empty datagram so it looks at its channels now
+pub fn wake(addr: SocketAddr) {
+    let any = match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let _ = UdpSocket::bind(any).and_then(|s| s.send_to(&[], addr));
+}
+
+// One command and its reply, clients are served one at a time
+fn client(conn: UnixStream, tx: &Sender<Request>, wake: SocketAddr) {
+    let _ = conn.set_read_timeout(Some(CLIENT_TIMEOUT));
+    let _ = conn.set_write_timeout(Some(CLIENT_TIMEOUT));
+    let mut line = String::new();
+    if BufReader::new(&conn).read_line(&mut line).is_err() {
+        return;
+    }
+    let reply = match serde_json::from_str::<Command>(&line) {
+        Ok(command) => {
+            notice!("Control: {:?}", command);
+            let (reply, answer) = mpsc::channel();
+            match tx.send(Request { command, reply }) {
+                Ok(()) => {
+                    self::wake(wake);
+                    answer
+                        .recv_timeout(ANSWER_TIMEOUT)
+                        .unwrap_or_else(|_| Reply::Error("no answer from the main loop".to_owned()))
+                }
+                Err(_) => Reply::Error("shutting down".to_owned()),
+            }
+        }
+        Err(e) => Reply::Error(format!("bad command: {}", e)),
+    };
+    if let Ok(json) = serde_json::to_string(&reply) {
+        let _ = writeln!(&conn, "{}", json);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::{
+    heard::Call,
+    peers::Peer,
+    system::System,
+    talkgroups::{Talkgroup, TgActivate},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/* db.rs
+    Everything that should survive a restart, kept in sled. Each kind of
+    record has its own tree and values are JSON, so new fields only need
+    a serde default. Anything that needs more than that bumps SCHEMA and
+    adds a step to MIGRATIONS.
+*/
+
+pub const SCHEMA: u32 = 1;
+
+/* MIGRATIONS[n] takes a database from schema n to n + 1. Schema 0 is a
+database written before versioning, which never held anything. */
+const MIGRATIONS: &[Migration] = &[|_| Ok(())];
+
+type Migration = fn(&sled::Db) -> Result<(), DbError>;
+
+const META: &str = "meta";
+const SUBSCRIPTIONS: &str = "subscriptions";
+const PEERS: &str = "peers";
+const HEARD: &str = "heard";
+const STATS: &str = "stats";
+
+pub struct Db {
+    db: sled::Db,
+    subscriptions: sled::Tree,
+    peers: sled::Tree,
+    heard: sled::Tree,
+    stats: sled::Tree,
+    // Most last-heard records we keep, and how many there are. sled counts by walking the tree.
+    heard_max: usize,
+    heard_len: Cell<usize>,
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Sled(sled::Error),
+    Json(serde_json::Error),
+    Schema { found: u32, supported: u32 },
+}
+
+// A user activated talkgroup, times are seconds since the epoch
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Subscription {
+    pub tg: u32,
+    pub slot: u8,
+    // Minutes without traffic before it is dropped
+    pub expire: u64,
+    pub last: u64,
+}
+
+// What a repeater told us about itself last time it logged in
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct PeerRecord {
+    pub id: u32,
+    pub callsign: String,
+    pub location: String,
+    pub description: String,
+    pub rx_freq: u32,
+    pub tx_freq: u32,
+    pub color_code: u8,
+    pub software: String,
+    pub package: String,
+    pub ip: String,
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+impl Db {
+    pub fn open(path: &str, heard_max: usize) -> Result<Self, DbError> {
+        let db = sled::open(path)?;
+        migrate(&db)?;
+        let heard = db.open_tree(HEARD)?;
+        Ok(Self {
+            subscriptions: db.open_tree(SUBSCRIPTIONS)?,
+            peers: db.open_tree(PEERS)?,
+            heard_len: Cell::new(heard.len()),
+            heard,
+            stats: db.open_tree(STATS)?,
+            db,
+            heard_max,
+        })
+    }
+
+    // Store a peer's user activated talkgroups, replacing what was there
+    pub fn save_subscriptions(&self, peer: &Peer) -> Result<(), DbError> {
+        let subs: Vec<Subscription> = peer
+            .talk_groups
+            .values()
+            .filter(|t| t.ua)
+            .map(|t| Subscription {
+                tg: t.id,
+                slot: t.sl,
+                expire: t.expire,
+                last: unix(t.time_stamp),
+            })
+            .collect();
+        if subs.is_empty() {
+            self.subscriptions.remove(peer.id.to_be_bytes())?;
+        } else {
+            put(&self.subscriptions, peer.id.to_be_bytes(), &subs)?;
+        }
+        Ok(())
+    }
+
+    // Give a peer back the talkgroups it had before, skipping any that have expired
+    pub fn restore_subscriptions(&self, peer: &mut Peer) -> Result<usize, DbError> {
+        let subs: Vec<Subscription> =
+            get(&self.subscriptions, peer.id.to_be_bytes())?.unwrap_or_default();
+        let mut n = 0;
+        for s in subs {
+            let last = UNIX_EPOCH + Duration::from_secs(s.last);
+            let age = last.elapsed().unwrap_or_default().as_secs();
+            if age > s.expire * 60 || peer.talk_groups.contains_key(&s.tg) {
+                continue;
+            }
+            let mut tg = Talkgroup::set(s.slot, TgActivate::Ua(s.tg), Some(s.expire));
+            tg.time_stamp = last;
+            peer.talk_groups.insert(s.tg, tg);
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    // Remember a repeater's details, keeping when we first saw it
+    pub fn save_peer(&self, peer: &Peer) -> Result<(), DbError> {
+        let key = peer.id.to_be_bytes();
+        let now = unix(SystemTime::now());
+        let first_seen = get::<PeerRecord>(&self.peers, key)?
+            .map(|r| r.first_seen)
+            .unwrap_or(now);
+        let record = PeerRecord {
+            id: peer.id,
+            callsign: peer.callsign.clone(),
+            location: peer.location.clone(),
+            description: peer.description.clone(),
+            rx_freq: peer.rx_freq,
+            tx_freq: peer.tx_freq,
+            color_code: peer.color_code,
+            software: peer.software.clone(),
+            package: peer.package.clone(),
+            ip: peer.ip.to_string(),
+            first_seen,
+            last_seen: now,
+        };
+        put(&self.peers, key, &record)
+    }
+
+    // Every repeater that has logged in, by ID
+    pub fn peers(&self) -> Result<Vec<PeerRecord>, DbError> {
+        all(&self.peers)
+    }
+
+    // Add a last-heard record, dropping the oldest once we have heard_max
+    pub fn add_heard(&self, call: &Call) -> Result<(), DbError> {
+        // Keyed by start time so the tree is in call order
+        let mut key = [0; 12];
+        key[..8].copy_from_slice(&call.start.to_be_bytes());
+        key[8..].copy_from_slice(&call.stream.to_be_bytes());
+        if self.heard.insert(key, serde_json::to_vec(call)?)?.is_none() {
+            self.heard_len.set(self.heard_len.get() + 1);
+        }
+        while self.heard_len.get() > self.heard_max {
+            if self.heard.pop_min()?.is_none() {
+                break;
+            }
+            self.heard_len.set(self.heard_len.get() - 1);
+        }
+        Ok(())
+    }
+
+    // Newest first
+    pub fn heard(&self) -> Result<Vec<Call>, DbError> {
+        let mut h: Vec<Call> = all(&self.heard)?;
+        h.reverse();
+        Ok(h)
+    }
+
+    pub fn save_stats(&self, system: &System, streams: usize) -> Result<(), DbError> {
+        put(&self.stats, "system", system)?;
+        put(&self.stats, "streams", &streams)
+    }
+
+    // The counters from last time, and the number of streams
+    pub fn stats(&self) -> Result<(System, usize), DbError> {
+        Ok((
+            get(&self.stats, "system")?.unwrap_or_default(),
+            get(&self.stats, "streams")?.unwrap_or_default(),
+        ))
+    }
+
+    pub fn flush(&self) -> Result<(), DbError> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+// Bring an older database up to SCHEMA, refuse one written by a newer dmrpal
+fn migrate(db: &sled::Db) -> Result<(), DbError> {
+    let meta = db.open_tree(META)?;
+    let mut version: u32 = get(&meta, "schema")?.unwrap_or(0);
+    if version > SCHEMA {
+        return Err(DbError::Schema {
+            found: version,
+            supported: SCHEMA,
+        });
+    }
+    while version < SCHEMA {
+        MIGRATIONS[version as usize](db)?;
+        version += 1;
+        put(&meta, "schema", &version)?;
+    }
+    Ok(())
+}
+
+pub fn unix(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn put<K: AsRef<[u8]>, T: Serialize + ?Sized>(
+    tree: &sled::Tree,
+    key: K,
+    value: &T,
+) -> Result<(), DbError> {
+    tree.insert(key, serde_json::to_vec(value)?)?;
+    Ok(())
+}
+
+fn get<T: DeserializeOwned>(
+    tree: &sled::Tree,
+    key: impl AsRef<[u8]>,
+) -> Result<Option<T>, DbError> {
+    match tree.get(key)? {
+        Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+        None => Ok(None),
+    }
+}
+
+fn all<T: DeserializeOwned>(tree: &sled::Tree) -> Result<Vec<T>, DbError> {
+    tree.iter()
+        .values()
+        .map(|v| Ok(serde_json::from_slice(&v?)?))
+        .collect()
+}
+
+impl From<sled::Error> for DbError {
+    fn from(e: sled::Error) -> Self {
+        DbError::Sled(e)
+    }
+}
+
+impl From<serde_json::Error> for DbError {
+    fn from(e: serde_json::Error) -> Self {
+        DbError::Json(e)
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::Sled(e) => write!(f, "database error: {}", e),
+            DbError::Json(e) => write!(f, "bad record in database: {}", e),
+            DbError::Schema { found, supported } => write!(
+                f,
+                "database schema {} is newer than this dmrpal supports ({})",
+                found, supported
+            ),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use crate::control;
+use crate::heard::{Call, CallType};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    net::SocketAddr,
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+/* directory.rs
+    Names for the numbers. A subscriber list (the RadioID user.csv or
+    users.json) and a talkgroup table are read from local files and kept
+    in memory. Either can be CSV or JSON, picked by the file extension.
+*/
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct User {
+    pub id: u32,
+    pub callsign: String,
+    pub name: String,
+    pub city: String,
+    pub state: String,
+    pub country: String,
+}
+
+// Where to read the lists from, an empty path leaves that list empty
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DirectoryConfig {
+    pub users: String,
+    pub talkgroups: String,
+}
+
+#[derive(Default)]
+pub struct Directory {
+    users: HashMap<u32, User>,
+    talkgroups: HashMap<u32, String>,
+}
+
+/* A full user list takes a while to read, so reloads happen on their own
+thread and are swapped in by the main loop. `wake` is where it listens. */
+pub struct Loader {
+    tx: Sender<Result<Directory, DirectoryError>>,
+    rx: Receiver<Result<Directory, DirectoryError>>,
+    wake: SocketAddr,
+    busy: bool,
+}
+
+#[derive(Debug)]
+pub enum DirectoryError {
+    Read(String, io::Error),
+    Csv(String, csv::Error),
+    Json(String, serde_json::Error),
+}
+
+impl Directory {
+    pub fn load(config: &DirectoryConfig) -> Result<Self, DirectoryError> {
+        let mut d = Self::default();
+        if !config.users.is_empty() {
+            d.users = load_users(&config.users)?;
+        }
+        if !config.talkgroups.is_empty() {
+            d.talkgroups = load_talkgroups(&config.talkgroups)?;
+        }
+        Ok(d)
+    }
+
+    pub fn users(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn talkgroups(&self) -> usize {
+        self.talkgroups.len()
+    }
+
+    pub fn user(&self, id: u32) -> Option<&User> {
+        self.users.get(&id)
+    }
+
+    pub fn talkgroup(&self, id: u32) -> Option<&str> {
+        self.talkgroups.get(&id).map(|n| n.as_str())
+    }
+
+    // A radio ID for the logs, with the callsign when we know it
+    pub fn radio(&self, id: u32) -> String {
+        match self.user(id) {
+            Some(u) if !u.callsign.is_empty() => format!("{} ({})", id, u.callsign),
+            _ => id.to_string(),
+        }
+    }
+
+    // A talkgroup for the logs, with its name when we know it
+    pub fn tg(&self, id: u32) -> String {
+        match self.talkgroup(id) {
+            Some(n) => format!("{} ({})", id, n),
+            None => id.to_string(),
+        }
+    }
+
+    // Fill in the names on a last-heard record
+    pub fn enrich(&self, call: &mut Call) {
+        if let Some(u) = self.user(call.src) {
+            call.callsign = Some(u.callsign.clone()).filter(|c| !c.is_empty());
+            call.name = Some(u.name.clone()).filter(|n| !n.is_empty());
+        }
+        call.dst_name = match call.call_type {
+            CallType::Group => self.talkgroup(call.dst).map(|n| n.to_owned()),
+            CallType::Private => self
+                .user(call.dst)
+                .map(|u| u.callsign.clone())
+                .filter(|c| !c.is_empty()),
+        };
+    }
+}
+
+impl Loader {
+    pub fn new(wake: SocketAddr) -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self {
+            tx,
+            rx,
+            wake,
+            busy: false,
+        }
+    }
+
+    // Start reading the lists, false if a reload is already running
+    pub fn start(&mut self, config: &DirectoryConfig) -> bool {
+        if self.busy {
+            return false;
+        }
+        self.busy = true;
+        let (config, tx, wake) = (config.clone(), self.tx.clone(), self.wake);
+        thread::spawn(move || {
+            let _ = tx.send(Directory::load(&config));
+            control::wake(wake);
+        });
+        true
+    }
+
+    // A finished reload, if there is one
+    pub fn done(&mut self) -> Option<Result<Directory, DirectoryError>> {
+        let loaded = self.rx.try_recv().ok()?;
+        self.busy = false;
+        Some(loaded)
+    }
+}
+
+fn is_json(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+}
+
+fn read(path: &str) -> Result<String, DirectoryError> {
+    fs::read_to_string(path).map_err(|e| DirectoryError::Read(path.to_owned(), e))
+}
+
+fn load_users(path: &str) -> Result<HashMap<u32, User>, DirectoryError> {
+    let text = read(path)?;
+    let users = if is_json(path) {
+        users_json(&text).map_err(|e| DirectoryError::Json(path.to_owned(), e))?
+    } else {
+        users_csv(&text).map_err(|e| DirectoryError::Csv(path.to_owned(), e))?
+    };
+    Ok(users.into_iter().map(|u| (u.id, u)).collect())
+}
+
+/* The RadioID CSV has a header of RADIO_ID,CALLSIGN,FIRST_NAME,LAST_NAME,
+CITY,STATE,COUNTRY. Columns are found by name so others work too, a file
+without a header is taken to be in that order. */
+fn users_csv(text: &str) -> Result<Vec<User>, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut records = reader.records();
+    let first = match records.next() {
+        Some(r) => r?,
+        None => return Ok(Vec::new()),
+    };
+    let names: Vec<String> = first
+        .iter()
+        .map(|h| h.trim().to_ascii_lowercase())
+        .collect();
+    let col = |want: &[&str]| names.iter().position(|n| want.contains(&n.as_str()));
+    let header = first
+        .get(0)
+        .is_some_and(|f| f.trim().parse::<u32>().is_err());
+    let cols = if header {
+        [
+            col(&["radio_id", "id", "dmr_id", "dmrid"]),
+            col(&["callsign", "call"]),
+            col(&["first_name", "fname", "name"]),
+            col(&["last_name", "surname"]),
+            col(&["city"]),
+            col(&["state"]),
+            col(&["country"]),
+        ]
+    } else {
+        [0, 1, 2, 3, 4, 5, 6].map(Some)
+    };
+    let field = |r: &csv::StringRecord, c: Option<usize>| {
+        c.and_then(|c| r.get(c)).unwrap_or("").trim().to_owned()
+    };
+
+    let mut users = Vec::new();
+    let rows = (!header).then_some(Ok(first)).into_iter().chain(records);
+    for r in rows {
+        let r = r?;
+        let Ok(id) = field(&r, cols[0]).parse() else {
+            continue;
+        };
+        users.push(User {
+            id,
+            callsign: field(&r, cols[1]),
+            name: join_name(&field(&r, cols[2]), &field(&r, cols[3])),
+            city: field(&r, cols[4]),
+            state: field(&r, cols[5]),
+            country: field(&r, cols[6]),
+        });
+    }
+    Ok(users)
+}
+
+// RadioID users.json, {"users": [...]}, or the API's {"results": [...]}, or just the list
+fn users_json(text: &str) -> Result<Vec<User>, serde_json::Error> {
+    let v: Value = serde_json::from_str(text)?;
+    let list = match v {
+        Value::Object(mut o) => o
+            .remove("users")
+            .or_else(|| o.remove("results"))
+            .unwrap_or_default(),
+        v => v,
+    };
+    let list = match list {
+        Value::Array(a) => a,
+        _ => Vec::new(),
+    };
+    let text = |u: &Value, keys: &[&str]| {
+        keys.iter()
+            .find_map(|k| u.get(k).and_then(|v| v.as_str()))
+            .unwrap_or("")
+            .trim()
+            .to_owned()
+    };
+    Ok(list
+        .iter()
+        .filter_map(|u| {
+            let id = ["radio_id", "id"].iter().find_map(|k| number(u.get(k)?))?;
+            Some(User {
+                id,
+                callsign: text(u, &["callsign"]),
+                name: join_name(&text(u, &["fname", "name"]), &text(u, &["surname"])),
+                city: text(u, &["city"]),
+                state: text(u, &["state"]),
+                country: text(u, &["country"]),
+            })
+        })
+        .collect())
+}
+
+fn load_talkgroups(path: &str) -> Result<HashMap<u32, String>, DirectoryError> {
+    let text = read(path)?;
+    if is_json(path) {
+        talkgroups_json(&text).map_err(|e| DirectoryError::Json(path.to_owned(), e))
+    } else {
+        talkgroups_csv(&text).map_err(|e| DirectoryError::Csv(path.to_owned(), e))
+    }
+}
+
+// id,name rows, anything that doesn't start with a number (like a header) is skipped
+fn talkgroups_csv(text: &str) -> Result<HashMap<u32, String>, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut tgs = HashMap::new();
+    for r in reader.records() {
+        let r = r?;
+        if let (Some(Ok(id)), Some(name)) = (r.get(0).map(|i| i.trim().parse()), r.get(1)) {
+            tgs.insert(id, name.trim().to_owned());
+        }
+    }
+    Ok(tgs)
+}
+
+// Either {"91": "Worldwide", ...} as BrandMeister has it, or a list of {"id", "name"}
+fn talkgroups_json(text: &str) -> Result<HashMap<u32, String>, serde_json::Error> {
+    let v: Value = serde_json::from_str(text)?;
+    Ok(match v {
+        Value::Object(o) => o
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.trim().parse().ok()?, v.as_str()?.to_owned())))
+            .collect(),
+        Value::Array(a) => a
+            .iter()
+            .filter_map(|t| {
+                let id = number(t.get("id")?)?;
+                Some((id, t.get("name")?.as_str()?.to_owned()))
+            })
+            .collect(),
+        _ => HashMap::new(),
+    })
+}
+
+// IDs turn up as numbers or strings
+fn number(v: &Value) -> Option<u32> {
+    match v {
+        Value::Number(n) => n.as_u64().and_then(|n| u32::try_from(n).ok()),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn join_name(first: &str, last: &str) -> String {
+    [first, last]
+        .iter()
+        .filter(|s| !s.is_empty())
+        .copied()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for DirectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DirectoryError::Read(path, e) => write!(f, "unable to read {}: {}", path, e),
+            DirectoryError::Csv(path, e) => write!(f, "error in {}: {}", path, e),
+            DirectoryError::Json(path, e) => write!(f, "error in {}: {}", path, e),
+        }
+    }
+}
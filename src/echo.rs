@@ -1,3 +1,4 @@
+use crate::debug;
 use std::time::SystemTime;
 
 // Frame struct that hold data to echo
@@ -21,20 +22,22 @@ impl Frame {
     }
 }
 
-impl Queue {
-    pub fn default() -> Self {
+impl Default for Queue {
+    fn default() -> Self {
         Self {
             echos: Vec::new(),
             la_time: SystemTime::now(),
         }
     }
+}
 
+impl Queue {
     pub fn has_items(&self) -> bool {
         self.echos.is_empty()
     }
 
     pub fn submit(&mut self, frame: Frame) {
-        println!("Submitting to Queue for stream ID: {}", frame.stream);
+        debug!(stream_id = frame.stream; "Echo frame queued");
         self.echos.push(frame);
         self.la_time = SystemTime::now();
     }
@@ -0,0 +1,209 @@
+use crate::heard::{Call, CallType};
+use serde_derive::Serialize;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{
+        mpsc::{self, RecvTimeoutError, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/* events.rs
+    Things happening as they happen, for dashboards. The main loop
+    publishes events here and every client of GET /api/events gets the ones
+    matching its filter as server-sent events, one JSON object per
+    message with its `type` field saying what happened. A client that can't keep up
+    is dropped rather than holding anything up.
+
+    Filters are query parameters and every one given has to match, events
+    without that field never match:
+        type=stream_start,stream_end   peer=<id>   tg=<id>   radio=<id>
+*/
+
+// Events a client can fall behind by before it is dropped
+const BACKLOG: usize = 256;
+// Comment sent to quiet clients so proxies keep the connection open
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    PeerLogin {
+        peer: u32,
+        callsign: String,
+        ip: String,
+    },
+    PeerLogout {
+        peer: u32,
+        reason: &'static str,
+    },
+    StreamStart {
+        stream: u32,
+        peer: u32,
+        src: u32,
+        dst: u32,
+        slot: u8,
+        private: bool,
+    },
+    StreamEnd(Call),
+    TgSubscribe {
+        peer: u32,
+        tg: u32,
+        slot: u8,
+    },
+    TgUnsubscribe {
+        peer: u32,
+        tg: u32,
+        reason: &'static str,
+    },
+    MasterState {
+        master: String,
+        from: String,
+        to: String,
+        reason: Option<String>,
+    },
+}
+
+// Who wants what, all must match
+#[derive(Default)]
+pub struct Filter {
+    types: Vec<String>,
+    peer: Option<u32>,
+    tg: Option<u32>,
+    radio: Option<u32>,
+}
+
+struct Client {
+    filter: Filter,
+    tx: SyncSender<Arc<str>>,
+}
+
+// Shared between the main loop and the API, cloning is cheap
+#[derive(Clone, Default)]
+pub struct Events {
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::PeerLogin { .. } => "peer_login",
+            Event::PeerLogout { .. } => "peer_logout",
+            Event::StreamStart { .. } => "stream_start",
+            Event::StreamEnd(_) => "stream_end",
+            Event::TgSubscribe { .. } => "tg_subscribe",
+            Event::TgUnsubscribe { .. } => "tg_unsubscribe",
+            Event::MasterState { .. } => "master_state",
+        }
+    }
+
+    fn peer(&self) -> Option<u32> {
+        match self {
+            Event::PeerLogin { peer, .. }
+            | Event::PeerLogout { peer, .. }
+            | Event::StreamStart { peer, .. }
+            | Event::TgSubscribe { peer, .. }
+            | Event::TgUnsubscribe { peer, .. } => Some(*peer),
+            Event::StreamEnd(c) => Some(c.peer),
+            Event::MasterState { .. } => None,
+        }
+    }
+
+    fn tg(&self) -> Option<u32> {
+        match self {
+            Event::StreamStart { dst, private, .. } if !private => Some(*dst),
+            Event::StreamEnd(c) if c.call_type == CallType::Group => Some(c.dst),
+            Event::TgSubscribe { tg, .. } | Event::TgUnsubscribe { tg, .. } => Some(*tg),
+            _ => None,
+        }
+    }
+
+    fn radio(&self) -> Option<u32> {
+        match self {
+            Event::StreamStart { src, .. } => Some(*src),
+            Event::StreamEnd(c) => Some(c.src),
+            _ => None,
+        }
+    }
+}
+
+impl Filter {
+    // From the query string of the request, Err names the bad parameter
+    pub fn parse(query: &HashMap<&str, &str>) -> Result<Self, String> {
+        let number = |k: &str| match query.get(k) {
+            Some(v) => v
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("{} is not a number", k)),
+            None => Ok(None),
+        };
+        Ok(Self {
+            types: query
+                .get("type")
+                .map(|t| t.split(',').map(|t| t.trim().to_owned()).collect())
+                .unwrap_or_default(),
+            peer: number("peer")?,
+            tg: number("tg")?,
+            radio: number("radio")?,
+        })
+    }
+
+    fn matches(&self, e: &Event) -> bool {
+        let want = |f: Option<u32>, v: Option<u32>| f.is_none() || f == v;
+        (self.types.is_empty() || self.types.iter().any(|t| t == e.kind()))
+            && want(self.peer, e.peer())
+            && want(self.tg, e.tg())
+            && want(self.radio, e.radio())
+    }
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, event: Event) {
+        let Ok(mut clients) = self.clients.lock() else {
+            return;
+        };
+        if clients.is_empty() {
+            return;
+        }
+        let Ok(json) = serde_json::to_string(&event) else {
+            return;
+        };
+        let json: Arc<str> = json.into();
+        // A full or closed channel means the client is gone or too slow
+        clients.retain(|c| !c.filter.matches(&event) || c.tx.try_send(json.clone()).is_ok());
+    }
+
+    // Hand the connection over to a thread of its own that feeds it events
+    pub fn subscribe(&self, filter: Filter, mut out: Box<dyn Write + Send>) {
+        let (tx, rx) = mpsc::sync_channel(BACKLOG);
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.push(Client { filter, tx });
+        }
+        let _ =
+            thread::Builder::new()
+                .name("events".to_owned())
+                .spawn(move || -> io::Result<()> {
+                    out.write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                    Cache-Control: no-cache\r\nConnection: close\r\n\r\n: connected\n\n",
+                    )?;
+                    out.flush()?;
+                    loop {
+                        match rx.recv_timeout(KEEPALIVE) {
+                            Ok(json) => write!(out, "data: {}\n\n", json)?,
+                            Err(RecvTimeoutError::Timeout) => out.write_all(b": keepalive\n\n")?,
+                            // Dropped for falling behind
+                            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                        }
+                        out.flush()?;
+                    }
+                });
+    }
+}
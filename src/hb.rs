@@ -1,25 +1,128 @@
+use crate::system;
 use hmac_sha256::Hash;
+use std::fmt;
 
 pub const DMRA: &[u8] = b"DMRA";
 pub const DMRD: &[u8] = b"DMRD";
 pub const MSTNAK: &[u8] = b"MSTNAK";
 pub const MSTPONG: &[u8] = b"MSTPONG";
-pub const MSTN: &[u8] = b"MSTN";
-pub const MSTP: &[u8] = b"MSTP";
-pub const MSTC: &[u8] = b"MSTC";
+pub const MSTCL: &[u8] = b"MSTCL";
 pub const RPTL: &[u8] = b"RPTL";
 pub const RPTPING: &[u8] = b"RPTPING";
 pub const RPTACK: &[u8] = b"RPTACK";
 pub const RPTK: &[u8] = b"RPTK";
 pub const RPTC: &[u8] = b"RPTC";
-pub const RPTP: &[u8] = b"RPTP";
-pub const RPTA: &[u8] = b"RPTA";
+pub const RPTCL: &[u8] = b"RPTCL";
 pub const RPTO: &[u8] = b"RPTO";
 pub const RPTS: &[u8] = b"RPTS";
+pub const RPTSBKN: &[u8] = b"RPTSBKN";
 
 pub const RX_BUFF_MAX: usize = 512;
 
+/* Packet sizes, including the opcode.
+DMRD is 53 bytes from MMDVM, some software appends BER and RSSI to make 55. */
+pub const DMRA_LEN: usize = 19;
+pub const DMRD_LEN: usize = 53;
+pub const DMRD_MAX: usize = 55;
+pub const MSTNAK_LEN: usize = 10;
+pub const MSTPONG_LEN: usize = 11;
+pub const MSTCL_LEN: usize = 9;
+pub const RPTL_LEN: usize = 8;
+pub const RPTPING_LEN: usize = 11;
+pub const RPTACK_LEN: usize = 10;
+pub const RPTK_LEN: usize = 40;
+pub const RPTC_LEN: usize = 302;
+pub const RPTCL_LEN: usize = 9;
+pub const RPTO_MIN: usize = 8;
+pub const RPTO_MAX: usize = 308;
+pub const RPTS_MIN: usize = 8;
+pub const RPTSBKN_LEN: usize = 11;
+
+// Errors returned when decoding a datagram
+#[derive(Debug, PartialEq)]
+pub enum PacketError {
+    Empty,
+    Unknown([u8; 4]),
+    Short {
+        opcode: &'static str,
+        expected: usize,
+        got: usize,
+    },
+    Long {
+        opcode: &'static str,
+        expected: usize,
+        got: usize,
+    },
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::Empty => write!(f, "empty packet"),
+            PacketError::Unknown(op) => {
+                write!(f, "unknown opcode {:X?}", op)
+            }
+            PacketError::Short {
+                opcode,
+                expected,
+                got,
+            } => write!(
+                f,
+                "{} too short, expected {} bytes got {}",
+                opcode, expected, got
+            ),
+            PacketError::Long {
+                opcode,
+                expected,
+                got,
+            } => write!(
+                f,
+                "{} too long, expected at most {} bytes got {}",
+                opcode, expected, got
+            ),
+        }
+    }
+}
+
+// Every Homebrew packet we know about, decoded.
+pub enum Packet {
+    Dmra(DMRAPacket),
+    Dmrd(DMRDPacket),
+    MstNak(u32),
+    MstPong(u32),
+    MstCl(u32),
+    RptL(u32),
+    RptPing(u32),
+    RptAck([u8; 4]),
+    RptK { id: u32, hash: [u8; 32] },
+    RptC(Box<RPTCPacket>),
+    RptCl(u32),
+    RptO(RPTOPacket),
+    RptS { id: u32, data: Vec<u8> },
+    RptSbkn(u32),
+}
+
+// DMRD frame types, bits 5-4 of byte 15
+pub const FT_VOICE: u8 = 0;
+pub const FT_VOICE_SYNC: u8 = 1;
+pub const FT_DATA_SYNC: u8 = 2;
+
+// DMRD call types, bit 6 of byte 15
+pub const CT_GROUP: u8 = 0;
+pub const CT_PRIVATE: u8 = 1;
+
+/* Talker alias, sent as a header (block 0) and up to three more blocks
+of 7 bytes each. The first byte of the header holds the format and length. */
+#[derive(Clone)]
+pub struct DMRAPacket {
+    pub id: u32,
+    pub src: u32,
+    pub block: u8,
+    pub data: [u8; 7],
+}
+
 // DMRD paclet structure
+#[derive(Clone)]
 pub struct DMRDPacket {
     pub seq: u8,
     pub src: u32,
@@ -34,20 +137,21 @@ pub struct DMRDPacket {
 }
 
 pub struct RPTCPacket {
-    callsign: [u8; 8],
-    rptrid: [u8; 4],
-    rx_freq: [u8; 9],
-    tx_freq: [u8; 9],
-    tx_pwr: [u8; 2],
-    color_code: [u8; 2],
-    latitude: [u8; 8],
-    longitude: [u8; 9],
-    height: [u8; 3],
-    location: [u8; 20],
-    description: [u8; 20],
-    url: [u8; 124],
-    software_id: [u8; 40],
-    package_id: [u8; 40],
+    pub callsign: [u8; 8],
+    pub rptrid: [u8; 4],
+    pub rx_freq: [u8; 9],
+    pub tx_freq: [u8; 9],
+    pub tx_pwr: [u8; 2],
+    pub color_code: [u8; 2],
+    pub latitude: [u8; 8],
+    pub longitude: [u8; 9],
+    pub height: [u8; 3],
+    pub location: [u8; 20],
+    pub description: [u8; 19],
+    pub slots: u8,
+    pub url: [u8; 124],
+    pub software_id: [u8; 40],
+    pub package_id: [u8; 40],
 }
 
 pub struct RPTLPacket {
@@ -59,15 +163,35 @@ pub struct RPTOPacket {
     pub options: String,
 }
 
+impl DMRAPacket {
+    pub fn construct(&self) -> [u8; DMRA_LEN] {
+        let mut b = [0; DMRA_LEN];
+        b[..4].copy_from_slice(DMRA);
+        b[4..8].copy_from_slice(&self.id.to_be_bytes());
+        b[8..11].copy_from_slice(&self.src.to_be_bytes()[1..]);
+        b[11] = self.block;
+        b[12..].copy_from_slice(&self.data);
+        b
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, PacketError> {
+        length("DMRA", buf, DMRA_LEN, DMRA_LEN)?;
+        let mut data = [0; 7];
+        data.copy_from_slice(&buf[12..19]);
+        Ok(Self {
+            id: be32(&buf[4..8]),
+            src: ((buf[8] as u32) << 16) | ((buf[9] as u32) << 8) | (buf[10] as u32),
+            block: buf[11],
+            data,
+        })
+    }
+}
+
 impl DMRDPacket {
-    // TODO: Data type 4 bits
     pub fn construct(&self) -> [u8; 55] {
         let mut cbuf = [0; 55];
 
-        cbuf[0] = 'D' as u8;
-        cbuf[1] = 'M' as u8;
-        cbuf[2] = 'R' as u8;
-        cbuf[3] = 'D' as u8;
+        cbuf[..4].copy_from_slice(DMRD);
 
         cbuf[4] = self.seq;
 
@@ -79,75 +203,73 @@ impl DMRDPacket {
         */
         cbuf[5] = (self.src >> 16) as u8;
         cbuf[6] = (self.src >> 8) as u8;
-        cbuf[7] = (self.src >> 0) as u8;
+        cbuf[7] = self.src as u8;
         cbuf[8] = (self.dst >> 16) as u8;
         cbuf[9] = (self.dst >> 8) as u8;
-        cbuf[10] = (self.dst >> 0) as u8;
+        cbuf[10] = self.dst as u8;
 
         cbuf[11..15].copy_from_slice(&self.rpt.to_be_bytes());
-
-        // This bit is broken because I suck at bitwise :-). Further testing before this can be used.
-        //if self.sl == 2 {
-        //    cbuf[15] = 1 << 1;
-        //}
-
-        //if self.ct == 1 {
-        //    cbuf[15] |= 1 << 1;
-        //}
-        //
-        //if self.ft == 1 {
-        //    cbuf[15] |= 1 << 2;
-        //} else if self.ft == 2 {
-        //    cbuf[15] |= 1 << 3;
-        //} else if self.ft == 3 {
-        //    cbuf[15] |= 3 << 2;
-        //}
-
+        cbuf[15] = self.bits();
         cbuf[16..20].copy_from_slice(&self.si.to_be_bytes());
         cbuf[20..55].copy_from_slice(&self.dd);
         cbuf
     }
 
-    // Parse DMRD packet
-    // TODO: Data type 4 bits
-    pub fn parse(buf: [u8; RX_BUFF_MAX]) -> Self {
-        let mut c_type = 0;
-        let mut f_type = 0;
-        let mut slot = 1;
-
-        if buf[15] & 0x80 == 0x80 {
-            slot = 2;
+    /* Byte 15 is a bitfield:
+        bit 7     slot (0 = TS1, 1 = TS2)
+        bit 6     call type (0 = group, 1 = private)
+        bits 5-4  frame type (0 = voice, 1 = voice sync, 2 = data sync)
+        bits 3-0  data type, or voice sequence (0 = A .. 5 = F) for voice frames
+    */
+    pub fn bits(&self) -> u8 {
+        let mut b = 0;
+
+        if self.sl == 2 {
+            b |= 0x80;
         }
 
-        if buf[15] & 0x40 == 0x40 {
-            c_type = 1;
-        } else if (buf[15] & 0x23) == 0x23 {
-            f_type = 1;
-        } else {
-            c_type = 0;
+        if self.ct == CT_PRIVATE {
+            b |= 0x40;
         }
 
+        b |= (self.ft & 0x03) << 4;
+        b |= self.dt & 0x0f;
+        b
+    }
+
+    // Parse DMRD packet
+    pub fn parse(buf: &[u8]) -> Result<Self, PacketError> {
+        length("DMRD", buf, DMRD_LEN, DMRD_MAX)?;
+
+        let slot = if buf[15] & 0x80 == 0x80 { 2 } else { 1 };
+
+        let c_type = if buf[15] & 0x40 == 0x40 {
+            CT_PRIVATE
+        } else {
+            CT_GROUP
+        };
+
+        // BER and RSSI are left as zero if the sender didn't include them
         let mut dmrd = [0; 35];
-        dmrd.clone_from_slice(&buf[20..55]);
+        dmrd[..buf.len() - 20].copy_from_slice(&buf[20..]);
 
-        Self {
+        Ok(Self {
             seq: buf[4],
             src: ((buf[5] as u32) << 16) | ((buf[6] as u32) << 8) | (buf[7] as u32),
             dst: ((buf[8] as u32) << 16) | ((buf[9] as u32) << 8) | (buf[10] as u32),
-            rpt: ((buf[11] as u32) << 24)
-                | ((buf[12] as u32) << 16)
-                | ((buf[13] as u32) << 8)
-                | (buf[14] as u32),
+            rpt: be32(&buf[11..15]),
             sl: slot,
             ct: c_type,
-            ft: f_type,
-            dt: 0,
-            si: ((buf[16] as u32) << 24)
-                | ((buf[17] as u32) << 16)
-                | ((buf[18] as u32) << 8)
-                | (buf[19] as u32),
+            ft: (buf[15] >> 4) & 0x03,
+            dt: buf[15] & 0x0f,
+            si: be32(&buf[16..20]),
             dd: dmrd,
-        }
+        })
+    }
+
+    // The voice terminator is a data sync frame carrying data type 2
+    pub fn is_terminator(&self) -> bool {
+        self.ft == FT_DATA_SYNC && self.dt == 2
     }
 }
 
@@ -163,21 +285,14 @@ impl RPTLPacket {
         b
     }
 
-    pub fn password_response(&self, buf: [u8; RX_BUFF_MAX]) -> [u8; 40] {
-        let password = b"PASSWORD";
+    pub fn password_response(&self, salt: &[u8; 4], password: &str) -> [u8; 40] {
         let mut bf = [0; 40];
-        let mut pbuf = [0; 12];
         bf[0] = b'R';
         bf[1] = b'P';
         bf[2] = b'T';
         bf[3] = b'K';
 
-        let ran = &buf[6..10];
-
-        pbuf[0..4].copy_from_slice(ran);
-        pbuf[4..12].copy_from_slice(password);
-
-        let result = Hash::hash(&pbuf);
+        let result = auth_hash(salt, password);
 
         bf[4..8].copy_from_slice(&self.id.to_be_bytes());
         bf[8..40].copy_from_slice(&result);
@@ -185,69 +300,609 @@ impl RPTLPacket {
         bf
     }
 
-    pub fn info(&self) -> [u8; 302] {
-        // This is for testing only and we must write a way to extract config, this is for POC
-        let mut b = [0x20; 302];
-        let rx_f = b"434525";
-        b[0] = b'R';
-        b[1] = b'P';
-        b[2] = b'T';
-        b[3] = b'C';
-        b[4..8].copy_from_slice(&self.id.to_be_bytes());
-        b[8] = b'M';
-        b[9] = b'X';
-        b[10] = b'0';
-        b[11] = b'W';
-        b[12] = b'V';
-        b[13] = b'V';
-        //b[16..20].copy_from_slice(&self.id.to_be_bytes());
-        b[16..rx_f.len() + 16].copy_from_slice(rx_f);
-        b[38..40].copy_from_slice(b"49");
-        b[40..42].copy_from_slice(b"49");
-        b[42..51].copy_from_slice(b"+50.42432");
-        b[51..60].copy_from_slice(b"+007.3412");
-        b[60..63].copy_from_slice(b"103");
-        b[63..84].copy_from_slice(b"NorwichNorwichNorwich");
-        b[82..103].copy_from_slice(b"Testing system 323455");
-
-        //97
-
-        b[228..239].copy_from_slice(b"DMRPaL:0.1B");
-        b[269..275].copy_from_slice(b"DMRPaL");
+    pub fn ping(&self) -> [u8; RPTPING_LEN] {
+        let mut b = [0; RPTPING_LEN];
+        b[..7].copy_from_slice(RPTPING);
+        b[7..].copy_from_slice(&self.id.to_be_bytes());
         b
     }
+}
 
-    pub fn info_parse(&self, buf: [u8; RX_BUFF_MAX]) {
-        println!("Peer duplex type: {}", buf[97])
+impl RPTOPacket {
+    // Exactly as long as the options, config load keeps them under RPTO_MAX
+    pub fn construct(id: u32, options: &str) -> Vec<u8> {
+        [b"RPTO".as_slice(), &id.to_be_bytes(), options.as_bytes()].concat()
     }
-    pub fn ping(&self) -> [u8; 8] {
-        let mut b = [0; 8];
-        b[0] = b'R';
-        b[1] = b'P';
-        b[2] = b'T';
-        b[3] = b'P';
-        b[4..].copy_from_slice(&self.id.to_be_bytes());
-        b
+
+    pub fn parse(buf: &[u8]) -> Result<Self, PacketError> {
+        length("RPTO", buf, RPTO_MIN, RPTO_MAX)?;
+
+        // Some repeaters pad the options with nulls
+        let options = String::from_utf8_lossy(&buf[8..]);
+        Ok(Self {
+            id: be32(&buf[4..8]),
+            options: options.trim_end_matches(['\0', ' ']).to_string(),
+        })
     }
 }
 
-impl RPTOPacket {
-    pub fn construct(id: u32, options: String) -> [u8; RX_BUFF_MAX] {
-        let mut b = [0; RX_BUFF_MAX];
-        let options_size = options.len();
-        b[0] = b'R';
-        b[1] = b'P';
-        b[2] = b'T';
-        b[3] = b'O';
-        b[4..8].copy_from_slice(&id.to_be_bytes());
-        b[8..options_size + 8].copy_from_slice(options.as_bytes());
+impl RPTCPacket {
+    // Build our own config packet, the counterpart to parse
+    pub fn create(id: u32, info: &system::Info) -> Self {
+        let mut p = Self {
+            callsign: [0; 8],
+            rptrid: id.to_be_bytes(),
+            rx_freq: [0; 9],
+            tx_freq: [0; 9],
+            tx_pwr: [0; 2],
+            color_code: [0; 2],
+            latitude: [0; 8],
+            longitude: [0; 9],
+            height: [0; 3],
+            location: [0; 20],
+            description: [0; 19],
+            slots: b'0' + info.slots.clamp(1, 4),
+            url: [0; 124],
+            software_id: [0; 40],
+            package_id: [0; 40],
+        };
+
+        field(&mut p.callsign, &info.callsign);
+        field(&mut p.rx_freq, &format!("{:09}", info.rx_freq));
+        field(&mut p.tx_freq, &format!("{:09}", info.tx_freq));
+        field(&mut p.tx_pwr, &format!("{:02}", info.power.min(99)));
+        field(
+            &mut p.color_code,
+            &format!("{:02}", info.color_code.min(15)),
+        );
+        field(&mut p.latitude, &format!("{:+08.4}", info.latitude));
+        field(&mut p.longitude, &format!("{:+09.4}", info.longitude));
+        field(&mut p.height, &format!("{:03}", info.height.min(999)));
+        field(&mut p.location, &info.location);
+        field(&mut p.description, &info.description);
+        field(&mut p.url, &info.url);
+        field(
+            &mut p.software_id,
+            &format!("DMRPaL:{}", env!("CARGO_PKG_VERSION")),
+        );
+        field(&mut p.package_id, "DMRPaL");
+        p
+    }
+
+    pub fn construct(&self) -> [u8; RPTC_LEN] {
+        let mut b = [0; RPTC_LEN];
+        b[0..4].copy_from_slice(RPTC);
+        b[4..8].copy_from_slice(&self.rptrid);
+        b[8..16].copy_from_slice(&self.callsign);
+        b[16..25].copy_from_slice(&self.rx_freq);
+        b[25..34].copy_from_slice(&self.tx_freq);
+        b[34..36].copy_from_slice(&self.tx_pwr);
+        b[36..38].copy_from_slice(&self.color_code);
+        b[38..46].copy_from_slice(&self.latitude);
+        b[46..55].copy_from_slice(&self.longitude);
+        b[55..58].copy_from_slice(&self.height);
+        b[58..78].copy_from_slice(&self.location);
+        b[78..97].copy_from_slice(&self.description);
+        b[97] = self.slots;
+        b[98..222].copy_from_slice(&self.url);
+        b[222..262].copy_from_slice(&self.software_id);
+        b[262..302].copy_from_slice(&self.package_id);
         b
     }
 
-    pub fn parse(buf: [u8; RX_BUFF_MAX]) -> Self {
-        Self {
-            id: ((buf[5] as u32) << 16) | ((buf[6] as u32) << 8) | (buf[7] as u32),
-            options: String::from_utf8_lossy(&buf[8..]).to_string(),
+    pub fn parse(buf: &[u8]) -> Result<Self, PacketError> {
+        length("RPTC", buf, RPTC_LEN, RPTC_LEN)?;
+
+        let mut p = Self {
+            callsign: [0; 8],
+            rptrid: [0; 4],
+            rx_freq: [0; 9],
+            tx_freq: [0; 9],
+            tx_pwr: [0; 2],
+            color_code: [0; 2],
+            latitude: [0; 8],
+            longitude: [0; 9],
+            height: [0; 3],
+            location: [0; 20],
+            description: [0; 19],
+            slots: buf[97],
+            url: [0; 124],
+            software_id: [0; 40],
+            package_id: [0; 40],
+        };
+
+        p.rptrid.copy_from_slice(&buf[4..8]);
+        p.callsign.copy_from_slice(&buf[8..16]);
+        p.rx_freq.copy_from_slice(&buf[16..25]);
+        p.tx_freq.copy_from_slice(&buf[25..34]);
+        p.tx_pwr.copy_from_slice(&buf[34..36]);
+        p.color_code.copy_from_slice(&buf[36..38]);
+        p.latitude.copy_from_slice(&buf[38..46]);
+        p.longitude.copy_from_slice(&buf[46..55]);
+        p.height.copy_from_slice(&buf[55..58]);
+        p.location.copy_from_slice(&buf[58..78]);
+        p.description.copy_from_slice(&buf[78..97]);
+        p.url.copy_from_slice(&buf[98..222]);
+        p.software_id.copy_from_slice(&buf[222..262]);
+        p.package_id.copy_from_slice(&buf[262..302]);
+        Ok(p)
+    }
+
+    pub fn id(&self) -> u32 {
+        be32(&self.rptrid)
+    }
+
+    pub fn callsign(&self) -> String {
+        text(&self.callsign)
+    }
+
+    // Frequencies are sent in Hz
+    pub fn rx_freq(&self) -> u32 {
+        number(&self.rx_freq).unwrap_or(0)
+    }
+
+    pub fn tx_freq(&self) -> u32 {
+        number(&self.tx_freq).unwrap_or(0)
+    }
+
+    pub fn power(&self) -> u16 {
+        number(&self.tx_pwr).unwrap_or(0)
+    }
+
+    pub fn color_code(&self) -> u8 {
+        number(&self.color_code).unwrap_or(0)
+    }
+
+    pub fn latitude(&self) -> f32 {
+        number(&self.latitude).unwrap_or(0.0)
+    }
+
+    pub fn longitude(&self) -> f32 {
+        number(&self.longitude).unwrap_or(0.0)
+    }
+
+    pub fn height(&self) -> u16 {
+        number(&self.height).unwrap_or(0)
+    }
+
+    pub fn location(&self) -> String {
+        text(&self.location)
+    }
+
+    pub fn description(&self) -> String {
+        text(&self.description)
+    }
+
+    /* Slots is an ASCII digit: 1 or 2 for a single timeslot, 3 for both
+    and 4 for a simplex hotspot. Anything else we treat as duplex. */
+    pub fn slots(&self) -> u8 {
+        match self.slots {
+            b'1'..=b'4' => self.slots - b'0',
+            _ => 3,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        text(&self.url)
+    }
+
+    pub fn software_id(&self) -> String {
+        text(&self.software_id)
+    }
+
+    pub fn package_id(&self) -> String {
+        text(&self.package_id)
+    }
+}
+
+impl Packet {
+    pub fn opcode(&self) -> &'static str {
+        match self {
+            Packet::Dmra(_) => "DMRA",
+            Packet::Dmrd(_) => "DMRD",
+            Packet::MstNak(_) => "MSTNAK",
+            Packet::MstPong(_) => "MSTPONG",
+            Packet::MstCl(_) => "MSTCL",
+            Packet::RptL(_) => "RPTL",
+            Packet::RptPing(_) => "RPTPING",
+            Packet::RptAck(_) => "RPTACK",
+            Packet::RptK { .. } => "RPTK",
+            Packet::RptC(_) => "RPTC",
+            Packet::RptCl(_) => "RPTCL",
+            Packet::RptO(_) => "RPTO",
+            Packet::RptS { .. } => "RPTS",
+            Packet::RptSbkn(_) => "RPTSBKN",
         }
     }
+
+    /* Decode a datagram. Longer opcodes are matched before the shorter ones
+    they share a prefix with (RPTCL before RPTC, RPTSBKN before RPTS). */
+    pub fn parse(buf: &[u8]) -> Result<Self, PacketError> {
+        if buf.is_empty() {
+            return Err(PacketError::Empty);
+        }
+
+        if buf.starts_with(DMRD) {
+            Ok(Packet::Dmrd(DMRDPacket::parse(buf)?))
+        } else if buf.starts_with(DMRA) {
+            Ok(Packet::Dmra(DMRAPacket::parse(buf)?))
+        } else if buf.starts_with(MSTNAK) {
+            length("MSTNAK", buf, MSTNAK_LEN, MSTNAK_LEN)?;
+            Ok(Packet::MstNak(be32(&buf[6..10])))
+        } else if buf.starts_with(MSTPONG) {
+            length("MSTPONG", buf, MSTPONG_LEN, MSTPONG_LEN)?;
+            Ok(Packet::MstPong(be32(&buf[7..11])))
+        } else if buf.starts_with(MSTCL) {
+            length("MSTCL", buf, MSTCL_LEN, MSTCL_LEN)?;
+            Ok(Packet::MstCl(be32(&buf[5..9])))
+        } else if buf.starts_with(RPTPING) {
+            length("RPTPING", buf, RPTPING_LEN, RPTPING_LEN)?;
+            Ok(Packet::RptPing(be32(&buf[7..11])))
+        } else if buf.starts_with(RPTACK) {
+            length("RPTACK", buf, RPTACK_LEN, RPTACK_LEN)?;
+            let mut salt = [0; 4];
+            salt.copy_from_slice(&buf[6..10]);
+            Ok(Packet::RptAck(salt))
+        } else if buf.starts_with(RPTCL) {
+            length("RPTCL", buf, RPTCL_LEN, RPTCL_LEN)?;
+            Ok(Packet::RptCl(be32(&buf[5..9])))
+        } else if buf.starts_with(RPTSBKN) {
+            length("RPTSBKN", buf, RPTSBKN_LEN, RPTSBKN_LEN)?;
+            Ok(Packet::RptSbkn(be32(&buf[7..11])))
+        } else if buf.starts_with(RPTL) {
+            length("RPTL", buf, RPTL_LEN, RPTL_LEN)?;
+            Ok(Packet::RptL(be32(&buf[4..8])))
+        } else if buf.starts_with(RPTK) {
+            length("RPTK", buf, RPTK_LEN, RPTK_LEN)?;
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&buf[8..40]);
+            Ok(Packet::RptK {
+                id: be32(&buf[4..8]),
+                hash,
+            })
+        } else if buf.starts_with(RPTC) {
+            Ok(Packet::RptC(Box::new(RPTCPacket::parse(buf)?)))
+        } else if buf.starts_with(RPTO) {
+            Ok(Packet::RptO(RPTOPacket::parse(buf)?))
+        } else if buf.starts_with(RPTS) {
+            length("RPTS", buf, RPTS_MIN, RX_BUFF_MAX)?;
+            Ok(Packet::RptS {
+                id: be32(&buf[4..8]),
+                data: buf[8..].to_vec(),
+            })
+        } else {
+            let mut op = [0; 4];
+            let n = buf.len().min(4);
+            op[..n].copy_from_slice(&buf[..n]);
+            Err(PacketError::Unknown(op))
+        }
+    }
+}
+
+// The RPTK challenge response is sha256(salt || password)
+pub fn auth_hash(salt: &[u8; 4], password: &str) -> [u8; 32] {
+    let mut h = Hash::new();
+    h.update(salt);
+    h.update(password.as_bytes());
+    h.finalize()
+}
+
+// Compare a peer's RPTK hash with what we expect without leaking timing
+pub fn auth_check(salt: &[u8; 4], password: &str, hash: &[u8; 32]) -> bool {
+    auth_hash(salt, password)
+        .iter()
+        .zip(hash.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+// Check a packet is within the size range for its opcode
+fn length(opcode: &'static str, buf: &[u8], min: usize, max: usize) -> Result<(), PacketError> {
+    if buf.len() < min {
+        return Err(PacketError::Short {
+            opcode,
+            expected: min,
+            got: buf.len(),
+        });
+    }
+    if buf.len() > max {
+        return Err(PacketError::Long {
+            opcode,
+            expected: max,
+            got: buf.len(),
+        });
+    }
+    Ok(())
+}
+
+// Left align text in a fixed width field, space padded and cut to fit
+fn field(buf: &mut [u8], value: &str) {
+    buf.fill(b' ');
+    let n = value.len().min(buf.len());
+    buf[..n].copy_from_slice(&value.as_bytes()[..n]);
+}
+
+// Fixed width text fields are space or null padded
+fn text(buf: &[u8]) -> String {
+    String::from_utf8_lossy(buf)
+        .trim_matches(['\0', ' '])
+        .to_string()
+}
+
+fn number<T: std::str::FromStr>(buf: &[u8]) -> Option<T> {
+    text(buf).parse().ok()
+}
+
+// Read a big endian u32 from the first 4 bytes
+fn be32(buf: &[u8]) -> u32 {
+    ((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) | (buf[3] as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* A call from 2345001 via repeater 234500101 laid out as MMDVMHost
+    sends it: opcode, seq, src, dst, repeater, byte 15, stream ID, the 33
+    byte burst, then BER and RSSI on the 55 byte frames. Headers and
+    terminators carry the data sync pattern DFF57D75DF5D mid-burst, voice A
+    the voice sync 755FD7DF75F7. */
+    const HEADER_TS2_GROUP: &str = "444d5244 00 23c829 0000eb 0dfa3005 a1 1a2b3c4d
+        89d23f0824128b2f330c5c7fd0adff57d75df5d70e269e0d37f2a74de452e6b438";
+    const VOICE_TS2_GROUP: [&str; 6] = [
+        "444d5244 01 23c829 0000eb 0dfa3005 90 1a2b3c4d
+        16099950d836f675cc81e74ef5e755fd7df75f74759531985d5d9dc9f81818e811",
+        "444d5244 02 23c829 0000eb 0dfa3005 81 1a2b3c4d
+        d30f21ddb66cad4a268d116ece1738f7d93d9c172411e20b8f6b0d549b6f03675a",
+        "444d5244 03 23c829 0000eb 0dfa3005 82 1a2b3c4d
+        0ff29d0da9953f48f1a09f76b5a170b33839263059f28c105d1fb17c2390c192cf",
+        "444d5244 04 23c829 0000eb 0dfa3005 83 1a2b3c4d
+        db8e81973e0becd7b03898d190f9ebdacc0cb1e29c658cda1495e60af593bd04cf",
+        "444d5244 05 23c829 0000eb 0dfa3005 84 1a2b3c4d
+        8f4ef8aa38922766581e27a1c08a6a63ec24ede6a46b4cb2424a23d5962217bead",
+        "444d5244 06 23c829 0000eb 0dfa3005 85 1a2b3c4d
+        5f301850c5a38fd547923a736994e3bf911a61dbe22e44158bae97ba94d0eda82f",
+    ];
+    const TERMINATOR_TS2_GROUP: &str = "444d5244 07 23c829 0000eb 0dfa3005 a2 1a2b3c4d
+        7f34b9b5df9e7769b10f4205b49dff57d75df5d037b64ce4228c38fb2918f135d2";
+    const HEADER_TS1_PRIVATE: &str = "444d5244 00 23c829 23ca82 0dfa3005 61 5e6f7081
+        74ec66a78795e761d17731af105dff57d75df5d7186d76b07e881ed162ae2eb154";
+    const VOICE_TS1_PRIVATE_RSSI: &str = "444d5244 01 23c829 23ca82 0dfa3005 50 5e6f7081
+        143e7d1bfbc7a2ea20b2f14c942755fd7df75f74273f98e2774cbd87ad5c90a958 02 4b";
+    const TERMINATOR_TS1_PRIVATE_RSSI: &str = "444d5244 02 23c829 23ca82 0dfa3005 62 5e6f7081
+        4972e6cc3ababced2057ee05cdedff57d75df5d206867347214cdd2055930d6eaf 00 49";
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        let hex: String = hex.split_whitespace().collect();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn dmrd(hex: &str) -> (Vec<u8>, DMRDPacket) {
+        let buf = bytes(hex);
+        let p = DMRDPacket::parse(&buf).unwrap();
+        (buf, p)
+    }
+
+    // 53 byte frames come back with zero BER and RSSI, everything else as sent
+    fn round_trip(hex: &str) {
+        let (buf, p) = dmrd(hex);
+        let out = p.construct();
+        assert_eq!(&out[..buf.len()], &buf[..]);
+        assert!(out[buf.len()..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn voice_header() {
+        let (_, p) = dmrd(HEADER_TS2_GROUP);
+        assert_eq!(p.seq, 0);
+        assert_eq!(p.src, 2345001);
+        assert_eq!(p.dst, 235);
+        assert_eq!(p.rpt, 234500101);
+        assert_eq!(p.si, 0x1a2b3c4d);
+        assert_eq!((p.sl, p.ct, p.ft, p.dt), (2, CT_GROUP, FT_DATA_SYNC, 1));
+        assert!(!p.is_terminator());
+        round_trip(HEADER_TS2_GROUP);
+    }
+
+    #[test]
+    fn voice_superframe() {
+        for (n, hex) in VOICE_TS2_GROUP.iter().enumerate() {
+            let (_, p) = dmrd(hex);
+            assert_eq!(p.seq as usize, n + 1);
+            assert_eq!((p.sl, p.ct), (2, CT_GROUP));
+            // Voice A has the sync, B to F carry their place in the superframe
+            match n {
+                0 => assert_eq!((p.ft, p.dt), (FT_VOICE_SYNC, 0)),
+                _ => assert_eq!((p.ft, p.dt), (FT_VOICE, n as u8)),
+            }
+            assert!(!p.is_terminator());
+            round_trip(hex);
+        }
+    }
+
+    #[test]
+    fn terminator() {
+        let (_, p) = dmrd(TERMINATOR_TS2_GROUP);
+        assert_eq!((p.sl, p.ft, p.dt), (2, FT_DATA_SYNC, 2));
+        assert!(p.is_terminator());
+        round_trip(TERMINATOR_TS2_GROUP);
+    }
+
+    #[test]
+    fn private_call_on_ts1() {
+        let (_, p) = dmrd(HEADER_TS1_PRIVATE);
+        assert_eq!(p.dst, 2345602);
+        assert_eq!((p.sl, p.ct, p.ft, p.dt), (1, CT_PRIVATE, FT_DATA_SYNC, 1));
+        round_trip(HEADER_TS1_PRIVATE);
+    }
+
+    #[test]
+    fn ber_and_rssi_are_kept() {
+        for hex in [VOICE_TS1_PRIVATE_RSSI, TERMINATOR_TS1_PRIVATE_RSSI] {
+            let (buf, p) = dmrd(hex);
+            assert_eq!(buf.len(), DMRD_MAX);
+            assert_eq!((p.sl, p.ct), (1, CT_PRIVATE));
+            assert_eq!(p.dd[33..], buf[53..]);
+            round_trip(hex);
+        }
+        let (_, p) = dmrd(VOICE_TS1_PRIVATE_RSSI);
+        assert_eq!((p.ft, p.dt), (FT_VOICE_SYNC, 0));
+    }
+
+    #[test]
+    fn dmrd_length() {
+        let buf = bytes(HEADER_TS2_GROUP);
+        assert!(matches!(
+            DMRDPacket::parse(&buf[..52]),
+            Err(PacketError::Short { expected: 53, .. })
+        ));
+        let mut long = bytes(VOICE_TS1_PRIVATE_RSSI);
+        long.push(0);
+        assert!(matches!(
+            DMRDPacket::parse(&long),
+            Err(PacketError::Long { expected: 55, .. })
+        ));
+    }
+
+    // Another dmrpal has to take what we send a master
+    #[test]
+    fn rpto_round_trip() {
+        let options = "TS1_1=23526;TS1_2=1;TS2_1=840;UAT=15";
+        let buf = RPTOPacket::construct(234500101, options);
+        assert_eq!(buf.len(), 8 + options.len());
+        let rpto = RPTOPacket::parse(&buf).unwrap();
+        assert_eq!(rpto.id, 234500101);
+        assert_eq!(rpto.options, options);
+
+        let longest = "9".repeat(RPTO_MAX - RPTO_MIN);
+        assert!(RPTOPacket::parse(&RPTOPacket::construct(1, &longest)).is_ok());
+    }
+
+    // An opcode, repeater 234500101 and zeros up to `len`
+    fn packet(op: &[u8], len: usize) -> Vec<u8> {
+        let mut buf = [op, &234500101u32.to_be_bytes()].concat();
+        buf.resize(len, 0);
+        buf
+    }
+
+    // Opcodes sharing a prefix go to the longer one first
+    #[test]
+    fn prefix_dispatch() {
+        let id = 234500101;
+        let parse = |op, len| Packet::parse(&packet(op, len));
+        assert!(matches!(parse(RPTCL, RPTCL_LEN), Ok(Packet::RptCl(i)) if i == id));
+        assert!(matches!(parse(RPTC, RPTC_LEN), Ok(Packet::RptC(_))));
+        assert!(matches!(parse(RPTSBKN, RPTSBKN_LEN), Ok(Packet::RptSbkn(i)) if i == id));
+        assert!(matches!(
+            Packet::parse(&[&packet(RPTS, 8)[..], b"status"].concat()),
+            Ok(Packet::RptS { id: i, data }) if i == id && data == b"status"
+        ));
+        assert!(matches!(parse(RPTPING, RPTPING_LEN), Ok(Packet::RptPing(i)) if i == id));
+        assert!(matches!(parse(MSTPONG, MSTPONG_LEN), Ok(Packet::MstPong(i)) if i == id));
+
+        // The bare opcode is a short RPTCL, not an RPTC
+        assert_eq!(
+            Packet::parse(b"RPTCL").err(),
+            Some(PacketError::Short {
+                opcode: "RPTCL",
+                expected: RPTCL_LEN,
+                got: 5
+            })
+        );
+        assert_eq!(Packet::parse(b"").err(), Some(PacketError::Empty));
+        assert_eq!(
+            Packet::parse(b"XYZ").err(),
+            Some(PacketError::Unknown(*b"XYZ\0"))
+        );
+    }
+
+    #[test]
+    fn truncated_packets() {
+        let shortest = [
+            ("DMRA", DMRA, DMRA_LEN),
+            ("DMRD", DMRD, DMRD_LEN),
+            ("MSTNAK", MSTNAK, MSTNAK_LEN),
+            ("MSTPONG", MSTPONG, MSTPONG_LEN),
+            ("MSTCL", MSTCL, MSTCL_LEN),
+            ("RPTL", RPTL, RPTL_LEN),
+            ("RPTPING", RPTPING, RPTPING_LEN),
+            ("RPTACK", RPTACK, RPTACK_LEN),
+            ("RPTK", RPTK, RPTK_LEN),
+            ("RPTC", RPTC, RPTC_LEN),
+            ("RPTCL", RPTCL, RPTCL_LEN),
+            ("RPTO", RPTO, RPTO_MIN),
+            ("RPTS", RPTS, RPTS_MIN),
+            ("RPTSBKN", RPTSBKN, RPTSBKN_LEN),
+        ];
+        for (opcode, op, len) in shortest {
+            let buf = packet(op, len);
+            assert!(Packet::parse(&buf).is_ok(), "{}", opcode);
+            assert_eq!(
+                Packet::parse(&buf[..len - 1]).err(),
+                Some(PacketError::Short {
+                    opcode,
+                    expected: len,
+                    got: len - 1
+                }),
+                "{}",
+                opcode
+            );
+        }
+    }
+
+    // Our RPTC to a master has to read back as we meant it
+    #[test]
+    fn rptc_round_trip() {
+        let info = system::Info {
+            callsign: "M0ABCDEFGH".to_owned(),
+            rx_freq: 439000000,
+            tx_freq: 430000000,
+            power: 120,
+            color_code: 1,
+            latitude: -1.5,
+            longitude: 52.25,
+            height: 12,
+            location: "Norwich".to_owned(),
+            description: "A description longer than 19".to_owned(),
+            url: "https://example.org".to_owned(),
+            slots: 9,
+        };
+        let buf = RPTCPacket::create(234500101, &info).construct();
+        assert_eq!(&buf[..8], b"RPTC\x0d\xfa\x30\x05");
+
+        // Text is space padded or cut short to fit
+        assert_eq!(&buf[8..16], b"M0ABCDEF");
+        assert_eq!(&buf[16..34], b"439000000430000000");
+        assert_eq!(&buf[34..38], b"9901");
+        assert_eq!(&buf[38..58], b"-01.5000+052.2500012");
+        assert_eq!(&buf[58..78], b"Norwich             ");
+        assert_eq!(&buf[78..97], b"A description longe");
+        assert_eq!(buf[97], b'4');
+        assert_eq!(&buf[98..117], b"https://example.org");
+        assert!(buf[117..222].iter().all(|b| *b == b' '));
+
+        let rptc = RPTCPacket::parse(&buf).unwrap();
+        assert_eq!(rptc.construct(), buf);
+        assert_eq!(rptc.id(), 234500101);
+        assert_eq!(rptc.callsign(), "M0ABCDEF");
+        assert_eq!(rptc.rx_freq(), 439000000);
+        assert_eq!(rptc.tx_freq(), 430000000);
+        assert_eq!(rptc.power(), 99);
+        assert_eq!(rptc.color_code(), 1);
+        assert_eq!(rptc.latitude(), -1.5);
+        assert_eq!(rptc.longitude(), 52.25);
+        assert_eq!(rptc.height(), 12);
+        assert_eq!(rptc.location(), "Norwich");
+        assert_eq!(rptc.description(), "A description longe");
+        assert_eq!(rptc.slots(), 4);
+        assert_eq!(rptc.url(), "https://example.org");
+        assert_eq!(
+            rptc.software_id(),
+            format!("DMRPaL:{}", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(rptc.package_id(), "DMRPaL");
+    }
 }
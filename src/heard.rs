@@ -0,0 +1,113 @@
+use crate::{db, hb, streams::Stream};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/* heard.rs
+    Last heard, a record of every call once its stream has ended. The
+    newest calls are kept in memory for lookups, and in the database as
+    well when there is one.
+*/
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CallType {
+    #[default]
+    Group,
+    Private,
+}
+
+// A finished call, times are seconds since the epoch
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Call {
+    pub stream: u32,
+    pub src: u32,
+    // Talkgroup, or radio ID for a private call
+    pub dst: u32,
+    // The repeater it came from
+    pub peer: u32,
+    pub slot: u8,
+    pub call_type: CallType,
+    pub start: u64,
+    pub end: u64,
+    pub duration_ms: u64,
+    pub frames: u32,
+    pub lost: u32,
+    pub timeout: bool,
+    pub alias: Option<String>,
+    // From the directory, when it knows them
+    pub callsign: Option<String>,
+    pub name: Option<String>,
+    pub dst_name: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct LastHeard {
+    // Newest first
+    calls: VecDeque<Call>,
+    max: usize,
+}
+
+impl From<&Stream> for Call {
+    fn from(s: &Stream) -> Self {
+        let duration = s.end_time.duration_since(s.start_time).unwrap_or_default();
+        Self {
+            stream: s.id,
+            src: s.src,
+            dst: s.dst,
+            peer: s.peer,
+            slot: s.slot,
+            call_type: if s.call_type == hb::CT_PRIVATE {
+                CallType::Private
+            } else {
+                CallType::Group
+            },
+            start: db::unix(s.start_time),
+            end: db::unix(s.end_time),
+            duration_ms: duration.as_millis() as u64,
+            frames: s.frames,
+            lost: s.lost,
+            timeout: s.time_out,
+            alias: s.alias.text.clone(),
+            callsign: None,
+            name: None,
+            dst_name: None,
+        }
+    }
+}
+
+impl LastHeard {
+    pub fn new(max: usize) -> Self {
+        Self {
+            calls: VecDeque::new(),
+            max,
+        }
+    }
+
+    // Start from what was saved, newest first
+    pub fn load(&mut self, calls: Vec<Call>) {
+        self.calls = calls.into_iter().take(self.max).collect();
+    }
+
+    pub fn add(&mut self, call: Call) {
+        self.calls.push_front(call);
+        self.calls.truncate(self.max);
+    }
+
+    pub fn len(&self) -> usize {
+        self.calls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    pub fn recent(&self) -> impl Iterator<Item = &Call> {
+        self.calls.iter()
+    }
+
+    // Calls that came in through a repeater
+    pub fn peer(&self, id: u32) -> impl Iterator<Item = &Call> {
+        self.calls.iter().filter(move |c| c.peer == id)
+    }
+}
@@ -1,12 +1,25 @@
-use std::{thread, time};
+use std::time;
 
+pub mod alias;
+pub mod api;
+pub mod control;
+pub mod db;
+pub mod directory;
 pub mod echo;
+pub mod events;
+pub mod hb;
+pub mod heard;
+pub mod logger;
 pub mod master;
+pub mod metrics;
 pub mod peers;
+pub mod router;
+pub mod rules;
 pub mod slot;
 pub mod streams;
 pub mod system;
 pub mod talkgroups;
+pub mod wheel;
 
 // Not yet used
 pub enum SystemState {
@@ -19,26 +32,3 @@ pub struct Systemstate {
     pub state: SystemState,
     pub time: time::SystemTime,
 }
-
-/* Log printing macro */
-#[macro_export]
-macro_rules! dprint {
-    ($verbose:expr;$mid:expr;$($arg:tt)*) => {{
-    if $verbose >= $mid {
-        match $mid{
-            1 => print!("CRITICAL: "),
-            2 => print!("WARNING: "),
-            3 => print!("NOTICE: "),
-            4 => print!("INFO: "),
-            10 => print!("DEBUG: "),
-            _=> {},
-        };
-        println!($($arg)*);
-    }
-    }};
-}
-
-/* Pause for X microseconds */
-pub fn sleep(time: u64) {
-    thread::sleep(time::Duration::from_micros(time));
-}
@@ -0,0 +1,436 @@
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/* logger.rs
+    Levelled logging with key=value fields. Use the macros:
+
+        info!("Peer connected");
+        warn!(peer_id = id, tg = tg; "Slot busy, dropping");
+
+    Each line gets a UTC timestamp, the level and the module it came from.
+    The [log] section of dmrpal.toml sets the level, a level per module,
+    and where lines go: stderr, a file that is rotated by size, syslog,
+    or any mix of them. Until init is called lines go to stderr at Info.
+*/
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Notice,
+    Info,
+    Debug,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // Unset falls back to the old numeric `verbose`
+    pub level: Option<Level>,
+    // Levels for modules and everything under them, "router" or "dmrpal::router"
+    pub modules: HashMap<String, Level>,
+    pub format: Format,
+    pub stderr: bool,
+    // An empty path turns the file off
+    pub file: String,
+    // MB before the file is rotated, and how many old files to keep
+    pub file_max: u64,
+    pub file_keep: usize,
+    pub syslog: bool,
+    pub facility: String,
+}
+
+pub struct Logger {
+    level: Level,
+    modules: Vec<(String, Level)>,
+    format: Format,
+    stderr: bool,
+    file: Option<LogFile>,
+    syslog: Option<(UnixDatagram, u8)>,
+}
+
+struct LogFile {
+    path: String,
+    file: File,
+    size: u64,
+    max: u64,
+    keep: usize,
+}
+
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+// The most verbose level any module logs at, so most calls are skipped without the lock
+static MAX: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+impl Level {
+    // The old dprint! verbosity numbers
+    pub fn from_verbose(v: u8) -> Self {
+        match v {
+            0 | 1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Notice,
+            4..=9 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+
+    // A name or an old verbosity number
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" | "critical" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warn),
+            "notice" => Some(Level::Notice),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            n => n.parse().ok().map(Self::from_verbose),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Notice => "NOTICE",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+
+    // Syslog severity
+    fn severity(self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Notice => 5,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: None,
+            modules: HashMap::new(),
+            format: Format::Text,
+            stderr: true,
+            file: String::new(),
+            file_max: 10,
+            file_keep: 5,
+            syslog: false,
+            facility: "daemon".to_owned(),
+        }
+    }
+}
+
+impl Logger {
+    pub fn new(config: &LogConfig, level: Level) -> Result<Self, String> {
+        let mut modules: Vec<(String, Level)> = config
+            .modules
+            .iter()
+            .map(|(m, l)| (m.strip_prefix("dmrpal::").unwrap_or(m).to_owned(), *l))
+            .collect();
+        // Longest first so the closest match wins
+        modules.sort_by_key(|(m, _)| std::cmp::Reverse(m.len()));
+        let file = match config.file.as_str() {
+            "" => None,
+            path => Some(LogFile::open(path, config.file_max, config.file_keep)?),
+        };
+        let syslog = match config.syslog {
+            false => None,
+            true => {
+                let facility = facility(&config.facility)
+                    .ok_or_else(|| format!("unknown syslog facility {}", config.facility))?;
+                let sock = UnixDatagram::unbound()
+                    .and_then(|s| s.connect("/dev/log").map(|_| s))
+                    .map_err(|e| format!("unable to open /dev/log: {}", e))?;
+                Some((sock, facility))
+            }
+        };
+        Ok(Self {
+            level,
+            modules,
+            format: config.format,
+            stderr: config.stderr,
+            file,
+            syslog,
+        })
+    }
+
+    fn level(&self, module: &str) -> Level {
+        let module = module.strip_prefix("dmrpal::").unwrap_or(module);
+        self.modules
+            .iter()
+            .find(|(m, _)| {
+                module == m
+                    || (module.starts_with(m.as_str()) && module[m.len()..].starts_with("::"))
+            })
+            .map_or(self.level, |(_, l)| *l)
+    }
+
+    fn write(
+        &mut self,
+        level: Level,
+        module: &str,
+        fields: &[(&str, String)],
+        msg: fmt::Arguments,
+    ) {
+        let msg = msg.to_string();
+        let line = match self.format {
+            Format::Text => text(level, module, fields, &msg),
+            Format::Json => json(level, module, fields, &msg),
+        };
+        if self.stderr {
+            let _ = writeln!(io::stderr(), "{}", line);
+        }
+        if let Some(f) = &mut self.file {
+            if let Err(e) = f.write(&line) {
+                let _ = writeln!(io::stderr(), "Unable to write log file {}: {}", f.path, e);
+            }
+        }
+        if let Some((sock, facility)) = &self.syslog {
+            // syslog adds its own timestamp
+            let mut kv = String::new();
+            for (k, v) in fields {
+                kv.push_str(&format!(" {}={}", k, quote(v)));
+            }
+            let _ = sock.send(
+                format!(
+                    "<{}>dmrpal[{}]: {}: {}{}",
+                    facility * 8 + level.severity(),
+                    std::process::id(),
+                    module,
+                    msg,
+                    kv
+                )
+                .as_bytes(),
+            );
+        }
+    }
+}
+
+impl LogFile {
+    fn open(path: &str, max: u64, keep: usize) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("unable to open log file {}: {}", path, e))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            size,
+            max: max * 1024 * 1024,
+            keep,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.max > 0 && self.size + line.len() as u64 + 1 > self.max {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // dmrpal.log -> dmrpal.log.1 -> dmrpal.log.2 ..., the oldest falls off the end
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(
+                    format!("{}.{}", self.path, n),
+                    format!("{}.{}", self.path, n + 1),
+                );
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+// Install the logger, replacing any earlier one
+pub fn init(logger: Logger) {
+    let max = logger
+        .modules
+        .iter()
+        .map(|(_, l)| *l)
+        .fold(logger.level, Level::max);
+    if let Ok(mut l) = LOGGER.lock() {
+        *l = Some(logger);
+        MAX.store(max as u8, Ordering::Relaxed);
+    }
+}
+
+// Used by the macros
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX.load(Ordering::Relaxed)
+}
+
+// Used by the macros
+pub fn log(level: Level, module: &str, fields: &[(&str, String)], msg: fmt::Arguments) {
+    let Ok(mut l) = LOGGER.lock() else {
+        return;
+    };
+    match l.as_mut() {
+        Some(l) if level <= l.level(module) => l.write(level, module, fields, msg),
+        Some(_) => {}
+        None => {
+            let _ = writeln!(
+                io::stderr(),
+                "{}",
+                text(level, module, fields, &msg.to_string())
+            );
+        }
+    }
+}
+
+fn text(level: Level, module: &str, fields: &[(&str, String)], msg: &str) -> String {
+    let mut line = format!(
+        "{} {:<6} {}: {}",
+        timestamp(SystemTime::now()),
+        level.name(),
+        module,
+        msg
+    );
+    for (k, v) in fields {
+        line.push_str(&format!(" {}={}", k, quote(v)));
+    }
+    line
+}
+
+fn json(level: Level, module: &str, fields: &[(&str, String)], msg: &str) -> String {
+    let mut o = serde_json::Map::new();
+    o.insert("time".into(), timestamp(SystemTime::now()).into());
+    o.insert("level".into(), level.name().to_ascii_lowercase().into());
+    o.insert("module".into(), module.into());
+    o.insert("msg".into(), msg.into());
+    for (k, v) in fields {
+        o.insert((*k).into(), v.as_str().into());
+    }
+    serde_json::Value::Object(o).to_string()
+}
+
+// Values with spaces or quotes in are quoted so the line still splits on spaces
+fn quote(v: &str) -> String {
+    if v.is_empty() || v.contains([' ', '"', '=']) {
+        format!("{:?}", v)
+    } else {
+        v.to_owned()
+    }
+}
+
+// RFC 3339 in UTC with milliseconds
+fn timestamp(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Days since the epoch to a civil date, after Howard Hinnant
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        d.subsec_millis()
+    )
+}
+
+fn facility(name: &str) -> Option<u8> {
+    Some(match name {
+        "user" => 1,
+        "daemon" => 3,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return None,
+    })
+}
+
+/* The macros. Fields go before the message, separated by a semicolon. */
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($k:ident = $v:expr),+ ; $($arg:tt)+) => {
+        if $crate::logger::enabled($level) {
+            $crate::logger::log(
+                $level,
+                module_path!(),
+                &[$((stringify!($k), $v.to_string())),+],
+                format_args!($($arg)+),
+            );
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::logger::enabled($level) {
+            $crate::logger::log($level, module_path!(), &[], format_args!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($t:tt)+) => { $crate::log!($crate::logger::Level::Error, $($t)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($t:tt)+) => { $crate::log!($crate::logger::Level::Warn, $($t)+) };
+}
+
+#[macro_export]
+macro_rules! notice {
+    ($($t:tt)+) => { $crate::log!($crate::logger::Level::Notice, $($t)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($t:tt)+) => { $crate::log!($crate::logger::Level::Info, $($t)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($t:tt)+) => { $crate::log!($crate::logger::Level::Debug, $($t)+) };
+}
@@ -1,515 +1,1191 @@
 use dmrpal::{
-    dprint, echo,
-    peers::{Peer, Peertype},
-    sleep, slot, streams, system,
+    api::{self, Api},
+    control::{self, Command, Control, Reply},
+    db::{Db, DbError},
+    debug,
+    directory::{self, Directory},
+    error,
+    events::{Event, Events},
+    hb,
+    heard::{Call, LastHeard},
+    info,
+    logger::{self, Level, Logger},
+    master::{Master, Masterstate},
+    metrics::Metrics,
+    notice,
+    peers::{self, Peer, Peerstate},
+    router::{self, Router},
+    rules::Scope,
+    streams, system,
     talkgroups::{Talkgroup, TgActivate},
+    warn,
+    wheel::Wheel,
 };
-use std::collections::hash_map::HashMap;
-use std::net::{IpAddr, Ipv4Addr, UdpSocket};
-use std::{env::args, io, str, time::SystemTime};
-
-mod hb;
-
-const USERACTIVATED_DISCONNECT_TG: u32 = 4000;
-
-#[derive(Debug, PartialEq)]
-enum Masterstate {
-    Disable,
-    LoginRequest,
-    LoginPassword,
-    Options,
-    Connected,
-    WaitingPong,
-    Logout,
+use signal_hook::{consts::SIGUSR1, iterator::Signals};
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::{
+    env::args,
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+const DEFAULT_CONFIG: &str = "dmrpal.toml";
+
+// Work scheduled on the timer wheel
+enum Timer {
+    Stats,
+    Streams,
+    Echo,
+    Beacon,
+    Master(usize),
+}
+
+fn stats(
+    mash: &HashMap<u32, Peer>,
+    masters: &[Master],
+    streams: &streams::Streams,
+    system: &system::System,
+    directory: &Directory,
+    last_heard: &LastHeard,
+) {
+    info!(peers = mash.len(), streams = streams.total; "Stats");
+    for (t, p) in mash {
+        let status = p.status.as_ref().map(|s| s.text()).unwrap_or_default();
+        let last = last_heard
+            .peer(*t)
+            .next()
+            .map(|c| {
+                format!(
+                    "{} -> {} TS{}",
+                    directory.radio(c.src),
+                    directory.tg(c.dst),
+                    c.slot
+                )
+            })
+            .unwrap_or_default();
+        info!(peer_id = t, callsign = p.callsign, ip = p.ip, rx = p.rx_bytes, tx = p.tx_bytes,
+            status = status, last_heard = last; "Peer");
+    }
+    for m in masters {
+        info!(master = m.name, state = format!("{:?}", m.state), rx = m.peer.rx_bytes,
+            tx = m.peer.tx_bytes, reconnects = m.reconnects; "Master");
+    }
+    info!(master_reconnects = system.master_reconnects, master_failures = system.master_failures,
+        master_naks = system.master_naks, master_closes = system.master_closes,
+        acl_denied = system.acl_denied, peer_closes = system.peer_closes,
+        streams_blocked = system.streams_blocked; "Totals");
+}
+
+/* Tell everyone we're going, then give the caller the exit status. Gives up
+once the deadline passes so a stuck socket can't hold up the exit. */
+fn closedown(
+    sock: &UdpSocket,
+    mash: &HashMap<u32, Peer>,
+    masters: &mut [Master],
+    deadline: Instant,
+) -> i32 {
+    notice!("Shutting down");
+    let _ = sock.set_write_timeout(Some(Duration::from_millis(100)));
+    let mut status = 0;
+    let peers = mash
+        .values()
+        .filter(|p| p.enabled)
+        .map(|p| ([hb::MSTCL, &p.id.to_be_bytes()].concat(), p.ip));
+    let ups = masters
+        .iter_mut()
+        .filter_map(|m| m.close().map(|tx| (tx, m.peer.ip)));
+    for (tx, addr) in peers.chain(ups) {
+        if Instant::now() >= deadline {
+            warn!("Shutdown deadline passed, not everyone was told");
+            status = 1;
+            break;
+        }
+        if let Err(e) = sock.send_to(&tx, addr) {
+            warn!("Error: {} sending close to {}", e, addr);
+            status = 1;
+        }
+    }
+    status
+}
+
+// Database writes that fail are logged, dmrpal carries on without them
+fn store(r: Result<(), DbError>) {
+    if let Err(e) = r {
+        warn!("Unable to save state: {}", e);
+    }
+}
+
+// Streams have ended, count the timeouts and remember the calls we didn't block
+fn heard(
+    ended: &[streams::Stream],
+    db: Option<&Db>,
+    events: &Events,
+    directory: &Directory,
+    last_heard: &mut LastHeard,
+    system: &mut system::System,
+    api: Option<&Api>,
+) {
+    system.total_timeouts += ended.iter().filter(|s| s.time_out).count();
+    for s in ended.iter().filter(|s| !s.blocked) {
+        let mut call = Call::from(s);
+        directory.enrich(&mut call);
+        info!(stream_id = call.stream, peer_id = call.peer, src = call.src, tg = call.dst,
+            slot = call.slot; "Heard: {} -> {} {}ms {} frames {} lost{}",
+            directory.radio(call.src), directory.tg(call.dst), call.duration_ms, call.frames,
+            call.lost, if call.timeout { " (timed out)" } else { "" });
+        if let Some(db) = db {
+            store(db.add_heard(&call));
+        }
+        events.publish(Event::StreamEnd(call.clone()));
+        last_heard.add(call);
+    }
+    if let Some(api) = api.filter(|_| !ended.is_empty()) {
+        api.heard(last_heard);
+    }
 }
 
-// Need to better handle close down gracefully but this will do for now.
-fn closedown() {
-    std::process::exit(0);
+/* Everything we keep between restarts that isn't written as it changes. sled's
+own thread writes it out every half second, only shutdown waits for a flush. */
+fn save(
+    db: &Db,
+    mash: &HashMap<u32, Peer>,
+    streams: &streams::Streams,
+    system: &system::System,
+) -> Result<(), DbError> {
+    for p in mash.values() {
+        db.save_subscriptions(p)?;
+    }
+    db.save_stats(system, streams.total)
+}
+
+// Log a master's state change, with the reason if it had to give up
+// `failures` is the count before, a failed lookup goes from Logout to Logout
+fn master_changed(events: &Events, m: &Master, was: Masterstate, failures: usize) {
+    if m.state == was && m.failures == failures {
+        return;
+    }
+    // Every ping goes through WaitingPong, that isn't news
+    let pinging = [Masterstate::Connected, Masterstate::WaitingPong];
+    if !(pinging.contains(&m.state) && pinging.contains(&was)) {
+        events.publish(Event::MasterState {
+            master: m.name.clone(),
+            from: format!("{:?}", was),
+            to: format!("{:?}", m.state),
+            reason: m
+                .last_failure
+                .filter(|_| m.state == Masterstate::Logout)
+                .map(|f| f.to_string()),
+        });
+    }
+    match (m.state, m.last_failure) {
+        (Masterstate::Logout, Some(f)) => {
+            warn!(master = m.name; "Dropped ({}), retrying in {:.1}s", f, m.retry.as_secs_f32());
+        }
+        (Masterstate::Closed, _) => {
+            warn!(master = m.name; "Closed the connection, logging in again in {}s", m.retry.as_secs());
+        }
+        (Masterstate::Connected, _) if was != Masterstate::WaitingPong => {
+            info!(master = m.name; "Connected");
+        }
+        _ => {
+            debug!(master = m.name; "{:?} -> {:?}", was, m.state);
+        }
+    }
+}
+
+// Ask every connected repeater to send a beacon, returns how many we asked
+fn beacon(sock: &UdpSocket, mash: &mut HashMap<u32, Peer>) -> usize {
+    let mut n = 0;
+    for p in mash
+        .values_mut()
+        .filter(|p| p.enabled && p.state == Peerstate::Connected)
+    {
+        if sock
+            .send_to(&[hb::RPTSBKN, &p.id.to_be_bytes()].concat(), p.ip)
+            .is_ok()
+        {
+            p.beacon = Some(SystemTime::now());
+            n += 1;
+        }
+    }
+    n
+}
+
+/* Forget a peer that has gone, by closing or being kicked. Only the streams it
+was sending end here, hands them back for heard(). Slots locked on the peer go
+with it, and calls from others carry on to everyone else. */
+fn drop_peer(
+    mash: &mut HashMap<u32, Peer>,
+    masters: &mut [Master],
+    streams: &mut streams::Streams,
+    events: &Events,
+    id: u32,
+    reason: &'static str,
+) -> Option<(Peer, Vec<streams::Stream>)> {
+    let p = mash.remove(&id)?;
+    notice!(peer_id = id, ip = p.ip; "Peer dropped ({})", reason);
+    events.publish(Event::PeerLogout { peer: id, reason });
+    let ended = streams.end_peer(id);
+    for s in &ended {
+        let dsts = mash
+            .values_mut()
+            .chain(masters.iter_mut().map(|m| &mut m.peer));
+        for d in dsts {
+            d.release(s.dst, s.slot);
+        }
+    }
+    Some((p, ended))
+}
+
+// Tell a peer we dropped to go away
+fn close(sock: &UdpSocket, p: &Peer) {
+    if let Err(e) = sock.send_to(&[hb::MSTCL, &p.id.to_be_bytes()].concat(), p.ip) {
+        warn!(peer_id = p.id; "Error sending close: {}", e);
+    }
+}
+
+// Answer a repeater logging in or pinging, it tries again if this is lost
+fn reply(sock: &UdpSocket, data: &[u8], to: SocketAddr) {
+    if let Err(e) = sock.send_to(data, to) {
+        warn!(ip = to; "Error replying: {}", e);
+    }
+}
+
+fn no_peer(id: u32) -> Reply {
+    Reply::Error(format!("peer {} is not logged in", id))
+}
+
+fn usage() {
+    println!("Usage: dmrpal [--config <path>] [--verbose <level>]");
 }
 
 fn main() {
-    let config = system::Config::load();
-    println!(
-        "My ID: {} | Master IP: {} | Verbose: {}",
-        config.my_id, config.master_ip, config.verbose
-    );
     let arg: Vec<String> = args().collect();
-    let mut verbose: u8 = config.verbose;
-    if arg.len() > 1 {
-        match arg[1].as_ref() {
-            "--verbose" | "-v" => match arg[2].parse::<u8>() {
-                Ok(v) => verbose = v,
-                Err(_) => dprint!(verbose;2;"Unable to set verbosity, using default"),
-            },
-            _ => {}
+    let mut config_path = DEFAULT_CONFIG.to_owned();
+    let mut verbose_arg = None;
+    let mut i = 1;
+    while i < arg.len() {
+        match (arg[i].as_ref(), arg.get(i + 1)) {
+            ("--config" | "-c", Some(path)) => config_path = path.to_owned(),
+            ("--verbose" | "-v", Some(v)) => verbose_arg = Some(v.to_owned()),
+            ("--help" | "-h", _) => {
+                usage();
+                return;
+            }
+            _ => {
+                usage();
+                std::process::exit(2);
+            }
         }
+        i += 2;
     }
-    dprint!(verbose;4;"Loading...");
 
-    let mut state: Masterstate = Masterstate::Disable;
+    let mut config = match system::Config::load(&config_path) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("CRITICAL: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // --verbose beats [log] level, which beats the old numeric verbose
+    let verbose = match verbose_arg.as_deref().map(Level::parse) {
+        Some(Some(l)) => Some(l),
+        Some(None) => {
+            eprintln!("CRITICAL: --verbose takes a level name or number");
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    let level = |c: &system::Config| {
+        verbose
+            .or(c.log.level)
+            .unwrap_or_else(|| Level::from_verbose(c.verbose))
+    };
+    match Logger::new(&config.log, level(&config)) {
+        Ok(l) => logger::init(l),
+        Err(e) => {
+            eprintln!("CRITICAL: {}", e);
+            std::process::exit(1);
+        }
+    }
+    info!(masters = config.masters.len(); "Loading...");
 
-    let mut streams = streams::Streams::init();
+    let mut streams =
+        streams::Streams::init(config.timers.stream_timeout, config.timers.stream_hang);
 
     let mut system = system::System::init();
 
+    let mut last_heard = LastHeard::new(config.database.heard);
+
+    let db = if config.database.path.is_empty() {
+        None
+    } else {
+        let opened = Db::open(&config.database.path, config.database.heard).and_then(|db| {
+            (system, streams.total) = db.stats()?;
+            last_heard.load(db.heard()?);
+            Ok(db)
+        });
+        match opened {
+            Ok(db) => Some(db),
+            Err(e) => {
+                error!("{}: {}", config.database.path, e);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    let mut directory = match Directory::load(&config.directory) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "Directory: {} users, {} talkgroups",
+        directory.users(),
+        directory.talkgroups()
+    );
+
     let mut mash: HashMap<u32, Peer> = HashMap::new();
+    // Logins waiting on RPTK, kept apart so a stray RPTL can't knock a live peer off
+    let mut pending: HashMap<u32, Peer> = HashMap::new();
 
-    let master_ip: std::net::SocketAddr = config.master_ip.parse().unwrap();
-
-    if config.my_id != 0 {
-        let mut master = Peer::new();
-        master.enabled = true;
-        master.callsign = "PHOENIXF".to_owned();
-        master.id = config.my_id;
-        master.ip = master_ip;
-        master.last_check = SystemTime::now();
-        master.peer_type = Peertype::All;
-        master.software = "IPSC2".to_owned();
-        master.talk_groups = HashMap::from([
-            (23526, Talkgroup::set(1, TgActivate::Static(23526), None)),
-            (2351, Talkgroup::set(1, TgActivate::Static(2351), None)),
-            (235, Talkgroup::set(1, TgActivate::Static(235), None)),
-            (840, Talkgroup::set(2, TgActivate::Static(840), None)),
-            (841, Talkgroup::set(2, TgActivate::Static(841), None)),
-            (844, Talkgroup::set(2, TgActivate::Static(844), None)),
-            (123, Talkgroup::set(1, TgActivate::Static(123), None)),
-            (113, Talkgroup::set(1, TgActivate::Static(113), None)),
-            (80, Talkgroup::set(1, TgActivate::Static(80), None)),
-            (81, Talkgroup::set(1, TgActivate::Static(81), None)),
-            (82, Talkgroup::set(1, TgActivate::Static(82), None)),
-            (83, Talkgroup::set(1, TgActivate::Static(83), None)),
-            (84, Talkgroup::set(1, TgActivate::Static(84), None)),
-            (3, Talkgroup::set(1, TgActivate::Static(3), None)),
-            (2, Talkgroup::set(1, TgActivate::Static(2), None)),
-            (1, Talkgroup::set(1, TgActivate::Static(1), None)),
-        ]);
-        master.options = "TS1_1=23526".to_owned();
-        // Insert the master into mash
-        mash.insert(config.my_id, master);
-        state = Masterstate::LoginRequest;
+    let mut masters: Vec<Master> = config
+        .masters
+        .iter()
+        .map(|m| Master::new(m, &config.timers))
+        .collect();
+    for m in &masters {
+        info!(master = m.name, ip = m.peer.ip; "State {:?}", m.state);
     }
 
+    // The main loop notices within a timer tick, if it doesn't we exit anyway after the deadline.
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopping = stop.clone();
+    let deadline = Duration::from_secs(config.timers.shutdown);
     ctrlc::set_handler(move || {
-        closedown();
+        if stopping.swap(true, Ordering::SeqCst) {
+            std::process::exit(1);
+        }
+        thread::sleep(deadline + Duration::from_secs(1));
+        error!("Shutdown took longer than {}s", deadline.as_secs());
+        std::process::exit(1);
     })
     .expect("Error setting Ctrl-C handler");
 
-    let sock = match UdpSocket::bind("0.0.0.0:55555") {
+    let events = Events::new();
+    let api = if config.api.bind.is_empty() {
+        None
+    } else {
+        match Api::start(&config.api.bind, events.clone()) {
+            Ok(api) => {
+                api.heard(&last_heard);
+                info!("API listening on {}", config.api.bind);
+                Some(api)
+            }
+            Err(e) => {
+                error!("API on {}: {}", config.api.bind, e);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    let sock = match UdpSocket::bind(&config.bind) {
         Ok(s) => s,
         Err(e) => {
-            dprint!(verbose;1;"There was an error binding: {}", e);
+            error!("There was an error binding: {}", e);
             std::process::exit(-1);
         }
     };
 
-    sock.set_nonblocking(true).unwrap();
+    // Other threads wake us with an empty datagram, on loopback if we listen everywhere
+    let mut wake = sock.local_addr().expect("Bound socket has an address");
+    if wake.ip().is_unspecified() {
+        wake.set_ip(match wake {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let mut loader = directory::Loader::new(wake);
 
-    let mut d_counter = 31;
-    let mut payload_counter: usize = 0;
-    let mut stats_timer = SystemTime::now();
+    /* SIGUSR1 reloads the directory. Its thread wakes us so it's seen straight
+    away, the handler restarts our recv rather than interrupting it. */
+    let reload = Arc::new(AtomicBool::new(false));
+    let mut signals = Signals::new([SIGUSR1]).expect("Error setting SIGUSR1 handler");
+    let flag = reload.clone();
+    thread::Builder::new()
+        .name("signals".to_owned())
+        .spawn(move || {
+            for _ in signals.forever() {
+                flag.store(true, Ordering::SeqCst);
+                control::wake(wake);
+            }
+        })
+        .expect("Error starting the signal thread");
 
-    // This needs to be automatic but for now lets be dirty and set manually.
-    let dirty_master_options: bool = true;
+    let control = if config.control.socket.is_empty() {
+        None
+    } else {
+        match Control::start(&config.control.socket, wake) {
+            Ok(c) => {
+                info!("Control socket at {}", config.control.socket);
+                Some(c)
+            }
+            Err(e) => {
+                error!("Control socket: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    // Peers refused by dmrpalctl block, forgotten on restart
+    let mut blocked: HashSet<u32> = HashSet::new();
 
-    let myid = hb::RPTLPacket { id: config.my_id };
+    let mut d_counter = 31;
+    let mut payload_counter: usize = 0;
+    let mut wheel = Wheel::new(Duration::from_millis(10), 256);
+    wheel.schedule(Duration::from_secs(config.timers.stats), Timer::Stats);
+    wheel.schedule(Duration::from_secs(1), Timer::Streams);
+    if config.timers.beacon > 0 {
+        wheel.schedule(Duration::from_secs(config.timers.beacon), Timer::Beacon);
+    }
+    let mut echo_pending = false;
+    for i in 0..masters.len() {
+        wheel.schedule(Duration::ZERO, Timer::Master(i));
+    }
 
     let mut rx_buff = [0; hb::RX_BUFF_MAX];
+    let mut metrics = Metrics::new();
 
     loop {
-        // Print stats at least every 1 minute and check if a peer needs removing
-        match stats_timer.elapsed() {
-            Ok(t) => {
-                if t.as_secs() >= 60 {
-                    dprint!(verbose;4;"Number of logins: {}", mash.len());
-                    for (t, p) in &mash {
-                        dprint!(verbose;4;
-                            "Peer details\n\nID: {}\nCall: {}\nRX: {} TX: {}\nIP: {}",
-                            t, p.callsign, p.rx_bytes, p.tx_bytes, p.ip
-                        );
+        if stop.load(Ordering::SeqCst) {
+            let mut status = closedown(&sock, &mash, &mut masters, Instant::now() + deadline);
+            if let Some(c) = &control {
+                c.close();
+            }
+            stats(&mash, &masters, &streams, &system, &directory, &last_heard);
+            if let Some(db) = &db {
+                if let Err(e) = save(db, &mash, &streams, &system).and_then(|_| db.flush()) {
+                    error!("Unable to save state: {}", e);
+                    status = 1;
+                }
+            }
+            std::process::exit(status);
+        }
 
-                        dprint!(verbose;4;"Total Number of streams processed: {}", streams.total);
+        if reload.swap(false, Ordering::SeqCst) && !loader.start(&config.directory) {
+            notice!("Directory reload already running");
+        }
+        match loader.done() {
+            Some(Ok(d)) => {
+                directory = d;
+                info!(
+                    "Directory reloaded: {} users, {} talkgroups",
+                    directory.users(),
+                    directory.talkgroups()
+                );
+            }
+            Some(Err(e)) => warn!("Directory not reloaded: {}", e),
+            None => {}
+        }
+
+        // Commands from dmrpalctl
+        for req in control.iter().flat_map(|c| c.pending()) {
+            let reply = match req.command {
+                Command::Peers => {
+                    let mut peers: Vec<api::PeerStatus> =
+                        mash.values().map(|p| api::peer(p, &directory)).collect();
+                    peers.sort_by_key(|p| p.id);
+                    Reply::data(peers)
+                }
+                Command::Masters => {
+                    Reply::data(masters.iter().map(api::master).collect::<Vec<_>>())
+                }
+                Command::Peer { id } => match mash.get(&id) {
+                    Some(p) => Reply::data(api::peer(p, &directory)),
+                    None => no_peer(id),
+                },
+                Command::Kick { id } => {
+                    match drop_peer(&mut mash, &mut masters, &mut streams, &events, id, "kicked") {
+                        Some((p, ended)) => {
+                            close(&sock, &p);
+                            heard(
+                                &ended,
+                                db.as_ref(),
+                                &events,
+                                &directory,
+                                &mut last_heard,
+                                &mut system,
+                                api.as_ref(),
+                            );
+                            Reply::done(format!("Peer {} kicked", id))
+                        }
+                        None => no_peer(id),
+                    }
+                }
+                Command::Block { id } => {
+                    blocked.insert(id);
+                    pending.remove(&id);
+                    let dropped = drop_peer(
+                        &mut mash,
+                        &mut masters,
+                        &mut streams,
+                        &events,
+                        id,
+                        "blocked",
+                    );
+                    if let Some((p, ended)) = dropped {
+                        close(&sock, &p);
+                        heard(
+                            &ended,
+                            db.as_ref(),
+                            &events,
+                            &directory,
+                            &mut last_heard,
+                            &mut system,
+                            api.as_ref(),
+                        );
+                    }
+                    Reply::done(format!("Peer {} blocked", id))
+                }
+                Command::Unblock { id } => match blocked.remove(&id) {
+                    true => Reply::done(format!("Peer {} unblocked", id)),
+                    false => Reply::Error(format!("peer {} is not blocked", id)),
+                },
+                Command::Blocked => {
+                    let mut ids: Vec<u32> = blocked.iter().copied().collect();
+                    ids.sort();
+                    Reply::data(ids)
+                }
+                Command::AddTg { slot, .. } if !(1..=2).contains(&slot) => {
+                    Reply::Error(format!("slot must be 1 or 2, not {}", slot))
+                }
+                Command::AddTg { id, tg, slot, ua } => match mash.get_mut(&id) {
+                    Some(p) => {
+                        let how = match ua {
+                            true => TgActivate::Ua(tg),
+                            false => TgActivate::Static(tg),
+                        };
+                        p.talk_groups
+                            .insert(tg, Talkgroup::set(slot, how, Some(p.tg_expire)));
+                        info!(peer_id = id, tg = tg, slot = slot, ua = ua; "Added talkgroup by request");
+                        events.publish(Event::TgSubscribe { peer: id, tg, slot });
+                        if let Some(db) = &db {
+                            store(db.save_subscriptions(p));
+                        }
+                        Reply::done(format!("Added TG {} on TS{} to peer {}", tg, slot, id))
                     }
-                    stats_timer = SystemTime::now();
-                    mash.retain(|_, p| match p.last_check.elapsed() {
+                    None => no_peer(id),
+                },
+                Command::RemoveTg { id, tg } => match mash.get_mut(&id) {
+                    Some(p) => match p.talk_groups.remove(&tg) {
+                        Some(_) => {
+                            info!(peer_id = id, tg = tg; "Removed talkgroup by request");
+                            events.publish(Event::TgUnsubscribe {
+                                peer: id,
+                                tg,
+                                reason: "removed",
+                            });
+                            if let Some(db) = &db {
+                                store(db.save_subscriptions(p));
+                            }
+                            Reply::done(format!("Removed TG {} from peer {}", tg, id))
+                        }
+                        None => Reply::Error(format!("peer {} doesn't have TG {}", id, tg)),
+                    },
+                    None => no_peer(id),
+                },
+                Command::ClearUa { id } => match mash.get_mut(&id) {
+                    Some(p) => {
+                        let mut tgs = Vec::new();
+                        p.talk_groups.retain(|tg, t| {
+                            if t.ua {
+                                tgs.push(*tg);
+                            }
+                            !t.ua
+                        });
+                        info!(peer_id = id, tgs = tgs.len(); "Cleared UA talkgroups by request");
+                        for &tg in &tgs {
+                            events.publish(Event::TgUnsubscribe {
+                                peer: id,
+                                tg,
+                                reason: "cleared",
+                            });
+                        }
+                        if let Some(db) = &db {
+                            store(db.save_subscriptions(p));
+                        }
+                        Reply::done(format!(
+                            "Cleared {} UA talkgroups from peer {}",
+                            tgs.len(),
+                            id
+                        ))
+                    }
+                    None => no_peer(id),
+                },
+                // Nothing changes unless the config and logger both load, the directory follows
+                Command::Reload => {
+                    let loaded = system::Config::load(&config_path)
+                        .map_err(|e| e.to_string())
+                        .and_then(|c| Ok((Logger::new(&c.log, level(&c))?, c)));
+                    match loaded {
+                        Ok((l, c)) => {
+                            config.reload(c);
+                            logger::init(l);
+                            streams.timers(config.timers.stream_timeout, config.timers.stream_hang);
+                            notice!("Configuration reloaded");
+                            // The directory is swapped in when the loader is done, see loader.done()
+                            let directory = match loader.start(&config.directory) {
+                                true => "the directory is loading in the background",
+                                false => "a directory reload was already running",
+                            };
+                            Reply::done(format!(
+                                "Configuration reloaded, {}. Changes to bind, masters, database, \
+                                api and control need a restart",
+                                directory
+                            ))
+                        }
+                        Err(e) => {
+                            warn!("Configuration not reloaded: {}", e);
+                            Reply::Error(e)
+                        }
+                    }
+                }
+                Command::Stats => {
+                    Reply::data(api::stats(&mash, &masters, &streams, &system, &directory))
+                }
+                Command::Seen => match db.as_ref().map(Db::peers) {
+                    Some(Ok(peers)) => Reply::data(peers),
+                    Some(Err(e)) => Reply::Error(e.to_string()),
+                    None => Reply::Error("the database is turned off".to_owned()),
+                },
+            };
+            req.answer(reply);
+        }
+
+        // Built each time round so a reload takes effect
+        let router = Router {
+            disconnect_tg: config.disconnect_tg,
+            echo_tg: config.echo_tg,
+            echo_slot: config.echo_slot,
+            echo_delay: config.timers.echo_delay,
+            rules: &config.rules,
+        };
+
+        // Run whatever timers have fallen due, then wait for a packet or the next timer
+        for timer in wheel.expire(Instant::now()) {
+            match timer {
+                Timer::Stats => {
+                    stats(&mash, &masters, &streams, &system, &directory, &last_heard);
+                    mash.retain(|id, p| match p.last_check.elapsed() {
                         Ok(lc) => {
-                            if lc.as_secs() > 15 && p.id != config.my_id {
+                            if lc.as_secs() > config.timers.peer_timeout {
+                                notice!(peer_id = id; "Timed out");
+                                events.publish(Event::PeerLogout {
+                                    peer: *id,
+                                    reason: "timeout",
+                                });
                                 false
                             } else {
                                 //p.talk_groups.ua_clear();
-                                p.talk_groups.retain(|_, t| t.ua_clear());
+                                p.talk_groups.retain(|tg, t| {
+                                    let keep = t.ua_clear(config.timers.stream_hang);
+                                    if !keep {
+                                        info!(peer_id = id, tg = tg; "UA talkgroup expired");
+                                        events.publish(Event::TgUnsubscribe {
+                                            peer: *id,
+                                            tg: *tg,
+                                            reason: "expired",
+                                        });
+                                    }
+                                    keep
+                                });
                                 true
                             }
                         }
                         Err(e) => {
-                            dprint!(verbose;2;"Error parsing last check time: {}",e);
+                            warn!(peer_id = id; "Error parsing last check time: {}", e);
+                            events.publish(Event::PeerLogout {
+                                peer: *id,
+                                reason: "error",
+                            });
                             false
                         }
                     });
+                    if let Some(db) = &db {
+                        store(save(db, &mash, &streams, &system));
+                    }
+                    wheel.schedule(Duration::from_secs(config.timers.stats), Timer::Stats);
+                }
+                Timer::Beacon => {
+                    let n = beacon(&sock, &mut mash);
+                    debug!("Asked {} peers for a beacon", n);
+                    wheel.schedule(Duration::from_secs(config.timers.beacon), Timer::Beacon);
+                }
+                Timer::Streams => {
+                    let ended = streams.check();
+                    heard(
+                        &ended,
+                        db.as_ref(),
+                        &events,
+                        &directory,
+                        &mut last_heard,
+                        &mut system,
+                        api.as_ref(),
+                    );
+                    if let Some(api) = &api {
+                        api.update(&mash, &masters, &streams, &system, &directory, &metrics);
+                    }
+                    wheel.schedule(Duration::from_secs(1), Timer::Streams);
+                }
+                Timer::Echo => {
+                    for action in router.echo(&mut mash) {
+                        if let router::Action::Send { to, addr, data } = action {
+                            debug!("Sending echo to {} {:X?}", to, &data);
+                            if let Err(e) = sock.send_to(&data, addr) {
+                                warn!("Error: {} sending echo to {}", e, to);
+                            }
+                        }
+                    }
+                    // Try again later if a slot was busy
+                    echo_pending = mash.values().any(|p| !p.echo.echos.is_empty());
+                    if echo_pending {
+                        wheel.schedule(Duration::from_secs(1), Timer::Echo);
+                    }
+                }
+                Timer::Master(i) => {
+                    let m = &mut masters[i];
+                    let (was, failures) = (m.state, m.failures);
+                    if let Some(tx) = m.tick(&config.timers, &mut system) {
+                        debug!(master = m.name; "Sending {:?}", m.state);
+                        if let Err(e) = sock.send_to(&tx, m.peer.ip) {
+                            warn!(master = m.name; "Error sending: {}", e);
+                        }
+                    }
+                    master_changed(&events, m, was, failures);
+                    if let Some(wait) = m.wait(&config.timers) {
+                        wheel.schedule(wait, Timer::Master(i));
+                    }
                 }
             }
-            Err(_) => {}
         }
+        let wait = match wheel.next() {
+            Some(t) => t.saturating_duration_since(Instant::now()),
+            None => Duration::from_secs(1),
+        };
+        // A zero timeout means block forever
+        sock.set_read_timeout(Some(wait.max(Duration::from_millis(1))))
+            .unwrap();
 
         let (rx_byte, src) = match sock.recv_from(&mut rx_buff) {
             Ok(rs) => {
                 payload_counter += 1;
                 rs
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (
-                0,
-                std::net::SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
-            ),
+            // Nothing before the next timer
+            Err(ref e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
             Err(e) => {
-                dprint!(verbose;1;"There was an error listening: {}", e);
+                error!("There was an error listening: {}", e);
                 std::process::exit(-1);
             }
         };
 
-        // check the state of master connection
-        if let Some(master) = mash.get_mut(&config.my_id) {
-            match state {
-                Masterstate::Disable => {}
-                Masterstate::LoginRequest => {
-                    sock.send_to(&myid.password_response(rx_buff), master.ip)
-                        .unwrap();
-                    dprint!(verbose;4;"sending password");
-                    system.master_reconnects += 1;
-                    sleep(10000);
-                }
-                Masterstate::LoginPassword => {
-                    sock.send_to(&myid.info(), master.ip).unwrap();
-                    dprint!(verbose;4;"sending info");
-                    sleep(95000);
-                }
-                Masterstate::Connected => match master.last_check.elapsed() {
-                    Ok(t) => {
-                        if t.as_secs() > 15 {
-                            sock.send_to(
-                                &[hb::RPTPING, &master.id.to_be_bytes()].concat(),
-                                master.ip,
-                            )
-                            .unwrap();
-                            state = Masterstate::WaitingPong;
-                        }
-                    }
-                    Err(_) => {
-                        dprint!(verbose;2;"Error passing master last check time");
-                    }
-                },
-                Masterstate::WaitingPong => match master.last_check.elapsed() {
-                    Ok(t) => {
-                        if t.as_secs() > 30 {
-                            state = Masterstate::Logout;
-                        }
-                    }
-                    Err(_) => {
-                        dprint!(verbose;2;"Error passing master last check time");
-                    }
-                },
-                Masterstate::Logout => {
-                    // The master logged us out so lets try logging in again after 5 minutes.
-                    // TODO manage peer logins better.
-                    if let Ok(t) = master.last_check.elapsed() {
-                        if t.as_secs() > 300 {
-                            state = Masterstate::LoginRequest;
-                        }
-                    }
-                }
-
-                Masterstate::Options => {
-                    let options = hb::RPTOPacket::construct(
-                        config.my_id,
-                        "TS1_1=23526;TS1_2=1;TS1_3=235;TS2_1=840;TS2_2=841;TS2_3=844;".to_string(),
-                    );
-                    dprint!(verbose;4;"Sending options to master");
-                    sock.send_to(&options, master.ip).unwrap();
-                }
-            }
+        // Only sent to wake us up, for dmrpalctl, SIGUSR1 or a finished directory load
+        if rx_byte == 0 {
+            continue;
         }
 
-        match &rx_buff[..4] {
-            hb::DMRA => {
-                dprint!(verbose;2;"Todo! 1");
+        let packet = match hb::Packet::parse(&rx_buff[..rx_byte]) {
+            Ok(p) => p,
+            Err(e) => {
+                notice!("Dropping packet from {}: {}", src, e);
+                metrics.dropped("malformed");
+                continue;
             }
-            hb::DMRD => {
-                let hbp = hb::DMRDPacket::parse(rx_buff);
-
-                // Check to see if the sending peer is enabled
-                if !mash.contains_key(&hbp.rpt) && src != master_ip {
-                    continue;
-                }
+        };
+        metrics.packet(packet.opcode());
 
-                if streams.stream(hbp.si) {
-                    dprint!(verbose;3;"Stream: {}, Timeout", hbp.si);
-                    continue;
+        match packet {
+            hb::Packet::Dmra(dmra) => {
+                for action in router.alias(&dmra, src, &mash, &masters, &mut streams) {
+                    match action {
+                        router::Action::SendAlias { to, addr, data } => {
+                            if let Err(e) = sock.send_to(&data, addr) {
+                                warn!("Error: {} sending alias to {}", e, to);
+                            }
+                        }
+                        router::Action::Alias { stream, src, alias } => {
+                            info!(stream_id = stream, src = src;
+                                "Talker alias from {}: {}", directory.radio(src), alias);
+                        }
+                        _ => {}
+                    }
                 }
+            }
+            hb::Packet::Dmrd(hbp) => {
                 d_counter += 1;
 
                 if d_counter > 32 {
                     d_counter = 0;
-                    dprint!(verbose;10;
-                        "rf_src: {}, dest: {}, packet seq: {:x?} slot: {}, ctype: {}, stream id: {} payload count: {}",
-                        hbp.src, hbp.dst, hbp.seq, hbp.sl, hbp.ct, hbp.si, payload_counter
-                    );
+                    debug!(stream_id = hbp.si, peer_id = hbp.rpt, src = hbp.src, tg = hbp.dst, slot = hbp.sl;
+                        "DMRD seq: {:x?} ctype: {} payload count: {}", hbp.seq, hbp.ct, payload_counter);
                 }
-                let mut tx_buff: [u8; 55] = <[u8; 55]>::try_from(&rx_buff[..55]).unwrap();
 
-                // Repeat to peers who are members of the same talkgroup and peer type.
-                for p in mash.values_mut() {
-                    // Only repeat to peers which are enabled
-                    if !p.enabled {
-                        continue;
+                if let Some(p) = mash.get_mut(&hbp.rpt) {
+                    if p.ip == src {
+                        p.tx_bytes += rx_byte;
                     }
-                    // Check we can lock slot. If the peer is simplex check if either slot is locked
-                    match p.talk_groups.get_mut(&hbp.dst) {
-                        Some(tg) => {
-                            match hbp.sl {
-                                1 => {
-                                    if p.duplex == 4 {
-                                        if !p.slot.lock(slot::Slots::One(hbp.dst))
-                                            || !p.slot.lock(slot::Slots::Two(hbp.dst))
-                                        {
-                                            continue;
-                                        }
-                                    } else {
-                                        if !p.slot.lock(slot::Slots::One(hbp.dst)) {
-                                            continue;
-                                        }
-                                    }
-                                }
-                                2 => {
-                                    if p.duplex == 4 {
-                                        if !p.slot.lock(slot::Slots::Two(hbp.dst))
-                                            || !p.slot.lock(slot::Slots::One(hbp.dst))
-                                        {
-                                            continue;
-                                        }
-                                    } else {
-                                        if !p.slot.lock(slot::Slots::Two(hbp.dst)) {
-                                            continue;
-                                        }
+                }
+                if let Some(m) = masters.iter_mut().find(|m| m.peer.ip == src) {
+                    m.peer.tx_bytes += rx_byte;
+                }
+
+                for action in router.route(&hbp, src, &mut mash, &mut masters, &mut streams) {
+                    match action {
+                        router::Action::Send { to, addr, data } => {
+                            match sock.send_to(&data, addr) {
+                                Ok(s) => {
+                                    let p = match to {
+                                        Scope::Peer(id) => mash.get_mut(&id),
+                                        Scope::Master(i) => masters.get_mut(i).map(|m| &mut m.peer),
+                                    };
+                                    if let Some(p) = p {
+                                        p.rx_bytes += s;
                                     }
                                 }
-                                _ => {
-                                    eprintln!("Can't lock slot, invalid slot number!");
-                                    continue;
+                                Err(em) => {
+                                    warn!("Error: {} sending to {}", em, to)
                                 }
                             }
-                            if tg.sl == hbp.sl
-                                && p.ip != src
-                                && p.ip
-                                    != std::net::SocketAddr::new(
-                                        IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-                                        0,
-                                    )
-                            {
-                                // If we are sending to the master we need to rewrite the source ID
-                                if p.id == config.my_id {
-                                    tx_buff[11..15].copy_from_slice(&p.id.to_be_bytes());
-                                }
-                                match sock.send_to(&tx_buff, p.ip) {
-                                    Ok(s) => p.rx_bytes += s,
-                                    Err(em) => {
-                                        dprint!(verbose;2;"Error: {} sending to peer: {}", em, p.id)
-                                    }
-                                }
-                                tg.la = SystemTime::now();
-                            } else if tg.ua {
-                                // Reset the time stamp for the UA talkgroup
-                                tg.time_stamp = SystemTime::now();
+                        }
+                        router::Action::Dropped(router::Dropped::Unknown) => {
+                            metrics.dropped("unknown_peer");
+                        }
+                        router::Action::Dropped(router::Dropped::Timeout) => {
+                            notice!(stream_id = hbp.si, tg = hbp.dst; "Stream timed out");
+                            metrics.dropped("stream_timeout");
+                        }
+                        router::Action::Dropped(router::Dropped::TimedOut) => {
+                            debug!(stream_id = hbp.si; "Frame after the stream timed out");
+                            metrics.dropped("stream_timeout");
+                        }
+                        router::Action::Dropped(router::Dropped::Blocked) => {
+                            metrics.dropped("ingress_rule");
+                        }
+                        router::Action::Dropped(router::Dropped::Ingress(from, e)) => {
+                            system.streams_blocked += 1;
+                            metrics.dropped("ingress_rule");
+                            notice!(stream_id = hbp.si, tg = hbp.dst; "Stream from {} dropped: {}", from, e);
+                        }
+                        router::Action::Dropped(router::Dropped::Egress(to, e)) => {
+                            info!(stream_id = hbp.si, tg = hbp.dst; "Stream not sent to {}: {}", to, e);
+                        }
+                        router::Action::Started {
+                            stream,
+                            peer,
+                            src,
+                            dst,
+                            slot,
+                            private,
+                        } => {
+                            events.publish(Event::StreamStart {
+                                stream,
+                                peer,
+                                src,
+                                dst,
+                                slot,
+                                private,
+                            });
+                        }
+                        router::Action::Subscribe { peer, tg, slot } => {
+                            info!(peer_id = peer, tg = tg, slot = slot; "Added talkgroup");
+                            events.publish(Event::TgSubscribe { peer, tg, slot });
+                            if let (Some(db), Some(p)) = (&db, mash.get(&peer)) {
+                                store(db.save_subscriptions(p));
                             }
-
-                            if p.ip == src {
-                                p.tx_bytes += rx_byte;
+                        }
+                        router::Action::ClearUa { peer, tgs } => {
+                            info!(peer_id = peer, tgs = tgs.len(); "Cleared UA talkgroups");
+                            for tg in tgs {
+                                events.publish(Event::TgUnsubscribe {
+                                    peer,
+                                    tg,
+                                    reason: "disconnect",
+                                });
+                            }
+                            if let (Some(db), Some(p)) = (&db, mash.get(&peer)) {
+                                store(db.save_subscriptions(p));
                             }
                         }
-                        None => {
-                            // If no talkgroup is found for the peer then we subscribe the peer to the talkgroup requested.
-                            // If the peer does not request this talkgroup again in a 15 minute window the peer is auto-
-                            // matically unsubscribed.
-                            if p.ip == src && hbp.dst != USERACTIVATED_DISCONNECT_TG {
-                                p.talk_groups.insert(
-                                    hbp.dst,
-                                    Talkgroup::set(
-                                        hbp.sl,
-                                        TgActivate::Ua(hbp.dst),
-                                        Some(p.tg_expire),
-                                    ),
-                                );
-                                dprint!(verbose;4;
-                                    "Added TG: {} to peer: id-{} call-{} ",
-                                    &hbp.dst, &p.id, &p.callsign
+                        router::Action::Echo { peer } => {
+                            if !echo_pending {
+                                echo_pending = true;
+                                wheel.schedule(
+                                    Duration::from_secs(config.timers.echo_delay),
+                                    Timer::Echo,
                                 );
-                            } else if hbp.dst == USERACTIVATED_DISCONNECT_TG {
-                                // Remove all UA
-                                p.talk_groups.retain(|_, t| t.ua_clear());
                             }
+                            debug!(peer_id = peer; "Echo recording {:X?}", &rx_buff[..rx_byte]);
                         }
-                    }
-                    if hbp.dst == 9990 && hbp.sl == 2 && p.id == hbp.rpt && p.id != config.my_id {
-                        dprint!(verbose;10;"{:X?}", &rx_buff[..55]);
-                        p.echo(<[u8; 55]>::try_from(&rx_buff[..55]).unwrap(), hbp.si);
+                        // Only produced for DMRA
+                        router::Action::SendAlias { .. } | router::Action::Alias { .. } => {}
                     }
                 }
             }
-            hb::MSTN => {
-                dprint!(verbose;2;"Todo!4a");
-            }
-            hb::MSTP => {
-                if let Some(master) = mash.get_mut(&config.my_id) {
-                    master.last_check = SystemTime::now();
-                    state = Masterstate::Connected;
+            // Packets only a master sends us
+            ref p @ (hb::Packet::RptAck(_)
+            | hb::Packet::MstPong(_)
+            | hb::Packet::MstCl(_)
+            | hb::Packet::MstNak(_)) => match masters.iter_mut().position(|m| m.peer.ip == src) {
+                Some(i) => {
+                    let m = &mut masters[i];
+                    let (was, failures) = (m.state, m.failures);
+                    if let Some(tx) = m.handle(p, &config.info, &config.timers, &mut system) {
+                        if let Err(e) = sock.send_to(&tx, m.peer.ip) {
+                            warn!(master = m.name; "Error sending: {}", e);
+                        }
+                    }
+                    master_changed(&events, m, was, failures);
+                    // Only one timer per master, a reply puts it back
+                    if let Some(wait) = m.wait(&config.timers) {
+                        wheel.cancel(|t| matches!(t, Timer::Master(j) if *j == i));
+                        wheel.schedule(wait, Timer::Master(i));
+                    }
                 }
-            }
-            hb::MSTC => {
-                // We've received a disconnect request from the master.
-                if state == Masterstate::Connected {
-                    state = Masterstate::Logout;
+                None => {
+                    notice!("Dropping master packet from unknown address {}", src);
+                    metrics.dropped("unknown_address");
                 }
+            },
+            hb::Packet::RptL(id) => {
+                if blocked.contains(&id) {
+                    notice!(peer_id = id, ip = src; "Login refused, blocked by dmrpalctl");
+                    metrics.login_failed("blocked");
+                    reply(&sock, &[hb::MSTNAK, &id.to_be_bytes()].concat(), src);
+                    continue;
+                }
+                // Every login attempt gets its own salt, a repeat RPTL starts over.
+                // A logged in peer stays as it is until the new login gets past RPTK.
+                let mut peer = match Peer::login(id, src, &config) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        error!("Unable to generate login salt: {}", e);
+                        metrics.login_failed("salt");
+                        reply(&sock, &[hb::MSTNAK, &id.to_be_bytes()].concat(), src);
+                        continue;
+                    }
+                };
+                if let Err(e) = peer.acl(&config.acl) {
+                    notice!(peer_id = id, ip = src; "Login blocked: {}", e);
+                    system.acl_denied += 1;
+                    metrics.login_failed("acl");
+                    reply(&sock, &[hb::MSTNAK, &id.to_be_bytes()].concat(), src);
+                    continue;
+                }
+                info!(peer_id = id, ip = src; "Login requested");
+                if let Some(db) = &db {
+                    match db.restore_subscriptions(&mut peer) {
+                        Ok(0) => {}
+                        Ok(n) => {
+                            info!(peer_id = id; "{} talkgroups from last time", n)
+                        }
+                        Err(e) => warn!(peer_id = id; "Unable to restore talkgroups: {}", e),
+                    }
+                }
+                reply(&sock, &[hb::RPTACK, &peer.salt].concat(), src);
+                // Only RPTL adds to pending, so logins that never sent RPTK go here
+                pending.retain(|_, p| {
+                    p.last_check
+                        .elapsed()
+                        .is_ok_and(|lc| lc.as_secs() <= config.timers.peer_timeout)
+                });
+                pending.insert(id, peer);
             }
-            hb::RPTL => {
-                let mut peer = Peer::new();
-                peer.pid(&<[u8; 4]>::try_from(&rx_buff[4..8]).unwrap());
-                // Just send a predefined (random string). This should be random!
-                let randid = [0x0A, 0x7E, 0xD4, 0x98];
-                sock.send_to(&[hb::RPTACK, &rx_buff[4..8], &randid].concat(), src)
-                    .unwrap();
-            }
-            hb::RPTK => {
-                let mut peer = Peer::new();
-                peer.pid(&<[u8; 4]>::try_from(&rx_buff[4..8]).unwrap());
-                if !peer.acl() {
-                    dprint!(verbose;3;"Peer ID: {} is blocked", peer.id);
-                    sock.send_to(&[hb::MSTNAK, &rx_buff[4..8]].concat(), src)
-                        .unwrap();
+            hb::Packet::RptK { id, hash } => {
+                // A wrong answer uses up the salt, the peer has to start again with RPTL.
+                let mut p = match pending.remove(&id) {
+                    Some(p) if p.expect(src, &[Peerstate::LoginRequest]) => p,
+                    p => {
+                        // Someone else's RPTK mustn't spoil a login from another address
+                        if let Some(p) = p.filter(|p| p.ip != src) {
+                            pending.insert(id, p);
+                        }
+                        notice!(peer_id = id; "RPTK out of sequence");
+                        metrics.login_failed("sequence");
+                        reply(&sock, &[hb::MSTNAK, &id.to_be_bytes()].concat(), src);
+                        continue;
+                    }
+                };
+                if !hb::auth_check(&p.salt, config.auth.password(id), &hash) {
+                    warn!(peer_id = id, ip = src; "Incorrect password");
+                    metrics.login_failed("password");
+                    reply(&sock, &[hb::MSTNAK, &id.to_be_bytes()].concat(), src);
                     continue;
                 }
-                dprint!(verbose;4;"Peer: {} has logged in", peer.id);
-                peer.ip = src;
-                mash.insert(peer.id, peer);
-                sock.send_to(&[hb::RPTACK, &rx_buff[4..8]].concat(), src)
-                    .unwrap();
+                info!(peer_id = id; "Logged in");
+                p.state = Peerstate::Authenticated;
+                p.last_check = SystemTime::now();
+                if let Some(old) = mash.insert(id, p) {
+                    notice!(peer_id = id, ip = src; "Logged in again, was at {}", old.ip);
+                    events.publish(Event::PeerLogout {
+                        peer: id,
+                        reason: "login",
+                    });
+                }
+                reply(&sock, &[hb::RPTACK, &id.to_be_bytes()].concat(), src);
             }
-            hb::RPTC => {
-                let mut peer = Peer::new();
-                peer.pid(&<[u8; 4]>::try_from(&rx_buff[4..8]).unwrap());
-
-                match mash.get_mut(&peer.id) {
-                    Some(p) => {
+            hb::Packet::RptC(rptc) => {
+                match mash.get_mut(&rptc.id()) {
+                    Some(p) if p.expect(src, &[Peerstate::Authenticated]) => {
                         p.enabled = true;
-                        p.callsign = match str::from_utf8(&rx_buff[8..16]) {
-                            Ok(c) => c.to_owned(),
-                            Err(_) => "Unknown".to_owned(),
-                        };
-                        p.duplex = rx_buff[97] - 48;
-                        p.frequency = match str::from_utf8(&rx_buff[16..38]) {
-                            Ok(c) => c.to_owned(),
-                            Err(_) => "Unknown".to_owned(),
-                        };
-                        dprint!(verbose;4;"Callsign is: {}", p.callsign);
-                        dprint!(verbose;4;"Frequency is: {}", p.frequency);
-                        dprint!(verbose;4;"Peer duplex type is: {}", p.duplex);
-
-                        // To help set the correct offsets print info received in bytes
-                        dprint!(verbose;10;"Peer details raw");
-                        for (a, b) in rx_buff.iter().enumerate() {
-                            print!("{a}:{b:X}  ");
+                        p.state = Peerstate::Configured;
+                        p.config(&rptc);
+                        if let Some(db) = &db {
+                            store(db.save_peer(p));
                         }
-                        dprint!(verbose;10;"\n");
+                        info!(peer_id = p.id, callsign = p.callsign, rx_freq = p.rx_freq,
+                            tx_freq = p.tx_freq, duplex = p.duplex; "Configured");
+                        debug!(peer_id = p.id, power = p.power, cc = p.color_code, lat = p.latitude,
+                            long = p.longitude, height = p.height, location = p.location,
+                            software = p.software; "Details");
                     }
-                    None => {
-                        dprint!(verbose;4;"Unknown peer sent info {}", peer.id);
+                    _ => {
+                        notice!(peer_id = rptc.id(); "RPTC out of sequence");
+                        metrics.login_failed("sequence");
+                        reply(&sock, &[hb::MSTNAK, &rptc.rptrid].concat(), src);
                         continue;
                     }
                 }
-                sock.send_to(&[hb::RPTACK, &rx_buff[4..8]].concat(), src)
-                    .unwrap();
+                reply(&sock, &[hb::RPTACK, &rptc.rptrid].concat(), src);
             }
-            hb::RPTP => {
-                let mut peer = Peer::new();
-                peer.pid(&<[u8; 4]>::try_from(&rx_buff[7..11]).unwrap());
-
-                // Only send pong if we know about the peer.
-                // Also update the peer IP address if it has changed.
-                peer.ip = match mash.get_mut(&peer.id) {
-                    Some(p) => {
+            hb::Packet::RptPing(id) => {
+                // Only send pong if we know about the peer and it has finished logging in
+                // from this address. A repeater that moves has to log in again.
+                match mash.get_mut(&id) {
+                    Some(p)
+                        if p.expect(
+                            src,
+                            &[
+                                Peerstate::Configured,
+                                Peerstate::Options,
+                                Peerstate::Connected,
+                            ],
+                        ) =>
+                    {
                         p.last_check = SystemTime::now();
-                        if p.ip != src {
-                            p.ip = src;
+                        if p.state != Peerstate::Connected {
+                            info!(peer_id = p.id, callsign = p.callsign, ip = src; "Connected");
+                            p.state = Peerstate::Connected;
+                            events.publish(Event::PeerLogin {
+                                peer: p.id,
+                                callsign: p.callsign.clone(),
+                                ip: src.to_string(),
+                            });
                         }
-                        p.ip
                     }
-                    None => continue,
-                };
-
-                sock.send_to(&[hb::MSTPONG, &rx_buff[4..8]].concat(), peer.ip)
-                    .unwrap();
-            }
-            hb::RPTA => {
-                state = match state {
-                    Masterstate::LoginRequest => Masterstate::LoginPassword,
-                    Masterstate::LoginPassword => {
-                        if !dirty_master_options {
-                            Masterstate::Connected
-                        } else {
-                            Masterstate::Options
-                        }
-                    }
-                    Masterstate::Logout => Masterstate::Logout,
-                    Masterstate::Connected | Masterstate::Options => Masterstate::Connected,
                     _ => {
-                        dprint!(verbose;3;"RPTACK RECEIVED: UNKNOWN Masterstate");
-                        dprint!(verbose;3;"Master state: {:?}", state);
-                        Masterstate::Disable
+                        metrics.dropped("unknown_peer");
+                        reply(&sock, &[hb::MSTNAK, &id.to_be_bytes()].concat(), src);
+                        continue;
                     }
-                }
+                };
+
+                reply(&sock, &[hb::MSTPONG, &id.to_be_bytes()].concat(), src);
             }
-            hb::RPTO => {
+            hb::Packet::RptO(peer_options) => {
                 let mut peer = Peer::new();
-                let peer_options = hb::RPTOPacket::parse(rx_buff);
-                peer.pid(&<[u8; 4]>::try_from(&rx_buff[4..8]).unwrap());
-                dprint!(verbose;4;"Peer {}; has sent options:", peer.id);
+                peer.id = peer_options.id;
+                info!(peer_id = peer.id; "Options received");
                 match mash.get_mut(&peer.id) {
-                    Some(p) => {
+                    Some(p)
+                        if p.expect(
+                            src,
+                            &[
+                                Peerstate::Configured,
+                                Peerstate::Options,
+                                Peerstate::Connected,
+                            ],
+                        ) =>
+                    {
                         p.options = peer_options.options;
                         p.options();
-                        sock.send_to(&[hb::RPTACK, &rx_buff[4..8]].concat(), src)
-                            .unwrap();
+                        if p.state == Peerstate::Configured {
+                            p.state = Peerstate::Options;
+                        }
+                        reply(&sock, &[hb::RPTACK, &peer.id.to_be_bytes()].concat(), src);
+                    }
+                    _ => {
+                        notice!(peer_id = peer.id; "RPTO out of sequence");
+                        metrics.login_failed("sequence");
+                        reply(&sock, &[hb::MSTNAK, &peer.id.to_be_bytes()].concat(), src);
                     }
-                    None => continue,
                 };
             }
-            hb::RPTS => {
-                dprint!(verbose;2;"Todo!12");
-            }
-            _ => {
-                sleep(500);
-                /* If a peer has an echo Queue to play then process it in quiet time. The queue is only played after 5 seconds has passed since the user recorded the message.
-                Only when the queue has been played do we then drop the queue by replacing with the default.
-                This isn't really an efficient way of doing this but it works for now, we can always improve later.
-                 */
-                for p in mash.values_mut() {
-                    if !p.echo.has_items() {
-                        if let Ok(t) = p.echo.la_time.elapsed() {
-                            if t.as_secs() >= 5 {
-                                if p.lock(p.id, 2) {
-                                    continue;
-                                }
-                                dprint!(verbose;10;"Sending echo to peer: {}", p.id);
-                                for i in &p.echo.echos {
-                                    dprint!(verbose;10;"{:X?}", &i.data);
-                                    sock.send_to(&i.data, p.ip).unwrap();
-                                }
-                                p.echo = echo::Queue::default();
-                            }
+            hb::Packet::RptCl(id) => {
+                // The repeater is going away, don't wait for the sweep to notice.
+                match mash.get(&id) {
+                    // The peer list in the API catches up on the next one second update
+                    Some(p) if p.ip == src => {
+                        system.peer_closes += 1;
+                        let dropped =
+                            drop_peer(&mut mash, &mut masters, &mut streams, &events, id, "close");
+                        if let Some((_, ended)) = dropped {
+                            heard(
+                                &ended,
+                                db.as_ref(),
+                                &events,
+                                &directory,
+                                &mut last_heard,
+                                &mut system,
+                                api.as_ref(),
+                            );
                         }
                     }
+                    _ => {
+                        notice!(peer_id = id, ip = src; "Close from unknown address");
+                        metrics.dropped("unknown_address");
+                    }
+                }
+            }
+            hb::Packet::RptS { id, data } => match mash.get_mut(&id) {
+                Some(p) if p.ip == src => {
+                    let status = peers::Status::new(data);
+                    debug!(peer_id = id; "Status: {}", status.text());
+                    p.status = Some(status);
+                }
+                _ => {
+                    notice!(peer_id = id, ip = src; "Status from unknown address");
+                    metrics.dropped("unknown_address");
+                }
+            },
+            hb::Packet::RptSbkn(id) => {
+                // A master wants a beacon, pass it on to the repeaters that can send one.
+                if let Some(m) = masters.iter().find(|m| m.peer.ip == src) {
+                    let n = beacon(&sock, &mut mash);
+                    info!(master = m.name; "Beacon requested, asked {} peers", n);
+                } else if let Some(p) = mash.get_mut(&id).filter(|p| p.ip == src) {
+                    p.beacon_requests += 1;
+                    info!(peer_id = id; "Beacon requested");
+                } else {
+                    notice!(peer_id = id, ip = src; "Beacon request from unknown address");
+                    metrics.dropped("unknown_address");
                 }
-
-                streams.check();
             }
         }
-        rx_buff = [0; hb::RX_BUFF_MAX];
     }
 }
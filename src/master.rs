@@ -1,3 +1,397 @@
-pub fn test_master() {
-    println!("testing");
+use crate::{
+    hb,
+    peers::{Peer, Peerstate, Peertype},
+    rules::{Block, Direction, Filter},
+    system,
+    talkgroups::{Talkgroup, TgActivate},
+    warn,
+};
+use std::{
+    fmt,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/* master.rs
+    Upstream masters we log in to. Each master has its own login state
+    and timers, and a Peer that holds its talkgroups and slots so the
+    router can treat it like any other destination.
+
+    Login is RPTL -> RPTACK(salt) -> RPTK -> RPTACK -> RPTC -> RPTACK
+    -> RPTO -> RPTACK. Each step has to be answered within master_step
+    seconds, a timeout or MSTNAK puts us in Logout to wait out a
+    jittered backoff before starting again with RPTL.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Masterstate {
+    Disable,
+    LoginRequest,
+    LoginPassword,
+    Config,
+    Options,
+    Connected,
+    WaitingPong,
+    Logout,
+    // The master sent MSTCL, we wait master_retry before logging in again
+    Closed,
+}
+
+// Why we dropped back to Logout
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    Timeout(Masterstate),
+    Nak(Masterstate),
+    PongTimeout,
+    // The address has never resolved, we try again after the backoff
+    Resolve,
+}
+
+pub struct Master {
+    pub name: String,
+    // As written in the config, looked up again every time we log in
+    pub address: String,
+    pub password: String,
+    pub peer: Peer,
+    pub state: Masterstate,
+    pub ingress: Filter,
+    pub egress: Filter,
+    pub reconnects: usize,
+    pub failures: usize,
+    pub naks: usize,
+    pub closes: usize,
+    pub last_failure: Option<Failure>,
+    // How long we wait in Logout before trying again
+    pub retry: Duration,
+    // (our talkgroup, the master's talkgroup)
+    map: Vec<(u32, u32)>,
+    salt: [u8; 4],
+    // Failures since we were last connected
+    attempt: u32,
+    // When we entered the current state or last sent a ping
+    since: SystemTime,
+    // A lookup of `address` on its own thread, used by the next login
+    lookup: Option<Receiver<Option<SocketAddr>>>,
+}
+
+impl Master {
+    pub fn new(c: &system::MasterConfig, timers: &system::Timers) -> Self {
+        let mut peer = Peer::new();
+        peer.enabled = c.enabled;
+        peer.callsign = c.callsign.clone();
+        peer.id = c.id;
+        // Left unset if the lookup fails, tick() tries again before logging in
+        if let Some(ip) = resolve(&c.address) {
+            peer.ip = ip;
+        }
+        peer.peer_type = Peertype::All;
+        peer.state = Peerstate::Connected;
+        peer.software = "IPSC2".to_owned();
+        peer.slot.hang = timers.slot_hang;
+        peer.options = c.options.clone();
+        for tg in &c.talkgroups {
+            peer.talk_groups.insert(
+                tg.id,
+                Talkgroup::set(tg.slot, TgActivate::Static(tg.id), None),
+            );
+        }
+
+        Self {
+            name: c.name.clone(),
+            address: c.address.clone(),
+            password: c.password.clone(),
+            peer,
+            // Logout with no wait so the first tick sends RPTL
+            state: if c.enabled {
+                Masterstate::Logout
+            } else {
+                Masterstate::Disable
+            },
+            ingress: c.ingress.clone(),
+            egress: c.egress.clone(),
+            reconnects: 0,
+            failures: 0,
+            naks: 0,
+            closes: 0,
+            last_failure: None,
+            retry: Duration::ZERO,
+            map: c
+                .talkgroups
+                .iter()
+                .filter_map(|t| t.remote.map(|r| (t.id, r)))
+                .collect(),
+            salt: [0; 4],
+            attempt: 0,
+            since: SystemTime::now(),
+            lookup: None,
+        }
+    }
+
+    // Subscriber and talkgroup rules for this master
+    pub fn check(&self, dir: Direction, src: u32, dst: u32, group: bool) -> Result<(), Block> {
+        match dir {
+            Direction::Ingress => self.ingress.check(src, dst, group),
+            Direction::Egress => self.egress.check(src, dst, group),
+        }
+    }
+
+    // Translate a talkgroup from the master to ours
+    pub fn local_tg(&self, tg: u32) -> u32 {
+        match self.map.iter().find(|(_, r)| *r == tg) {
+            Some((l, _)) => *l,
+            None => tg,
+        }
+    }
+
+    // Translate one of our talkgroups to the master's
+    pub fn remote_tg(&self, tg: u32) -> u32 {
+        match self.map.iter().find(|(l, _)| *l == tg) {
+            Some((_, r)) => *r,
+            None => tg,
+        }
+    }
+
+    /* Called every time round the main loop. Returns a packet if it's
+    time to send this master something. */
+    pub fn tick(
+        &mut self,
+        timers: &system::Timers,
+        system: &mut system::System,
+    ) -> Option<Vec<u8>> {
+        let login = hb::RPTLPacket { id: self.peer.id };
+        let waited = self.since.elapsed().unwrap_or_default();
+        match self.state {
+            Masterstate::Disable => None,
+            Masterstate::LoginRequest
+            | Masterstate::LoginPassword
+            | Masterstate::Config
+            | Masterstate::Options => {
+                if waited >= Duration::from_secs(timers.master_step) {
+                    self.fail(Failure::Timeout(self.state), timers, system);
+                }
+                None
+            }
+            Masterstate::Connected | Masterstate::WaitingPong => {
+                let quiet = self.peer.last_check.elapsed().unwrap_or_default();
+                if quiet > Duration::from_secs(timers.master_timeout) {
+                    self.fail(Failure::PongTimeout, timers, system);
+                    return None;
+                }
+                if waited >= Duration::from_secs(timers.master_ping) {
+                    self.state = Masterstate::WaitingPong;
+                    self.since = SystemTime::now();
+                    return Some(login.ping().to_vec());
+                }
+                None
+            }
+            Masterstate::Logout | Masterstate::Closed => {
+                if waited < self.retry {
+                    return None;
+                }
+                if self.attempt > 0 || self.state == Masterstate::Closed {
+                    self.reconnects += 1;
+                    system.master_reconnects += 1;
+                }
+                // The master may have moved since we last logged in
+                self.lookup();
+                if self.peer.ip.ip().is_unspecified() {
+                    self.fail(Failure::Resolve, timers, system);
+                    return None;
+                }
+                self.state = Masterstate::LoginRequest;
+                self.since = SystemTime::now();
+                Some(login.request_login().to_vec())
+            }
+        }
+    }
+
+    // How long until tick() next has something to do
+    pub fn wait(&self, timers: &system::Timers) -> Option<Duration> {
+        let waited = self.since.elapsed().unwrap_or_default();
+        match self.state {
+            Masterstate::Disable => None,
+            Masterstate::LoginRequest
+            | Masterstate::LoginPassword
+            | Masterstate::Config
+            | Masterstate::Options => {
+                Some(Duration::from_secs(timers.master_step).saturating_sub(waited))
+            }
+            Masterstate::Connected | Masterstate::WaitingPong => {
+                let quiet = self.peer.last_check.elapsed().unwrap_or_default();
+                let timeout = Duration::from_secs(timers.master_timeout).saturating_sub(quiet);
+                let ping = Duration::from_secs(timers.master_ping).saturating_sub(waited);
+                Some(timeout.min(ping))
+            }
+            Masterstate::Logout | Masterstate::Closed => Some(self.retry.saturating_sub(waited)),
+        }
+    }
+
+    // A packet arrived from this master, returns a reply if one is needed
+    pub fn handle(
+        &mut self,
+        packet: &hb::Packet,
+        info: &system::Info,
+        timers: &system::Timers,
+        system: &mut system::System,
+    ) -> Option<Vec<u8>> {
+        let id = self.peer.id;
+        match packet {
+            hb::Packet::RptAck(ack) => {
+                let next = match self.state {
+                    Masterstate::LoginRequest => {
+                        self.salt = *ack;
+                        Masterstate::LoginPassword
+                    }
+                    Masterstate::LoginPassword => Masterstate::Config,
+                    Masterstate::Config if !self.peer.options.is_empty() => Masterstate::Options,
+                    Masterstate::Config | Masterstate::Options => {
+                        self.attempt = 0;
+                        self.retry = Duration::ZERO;
+                        self.peer.last_check = SystemTime::now();
+                        Masterstate::Connected
+                    }
+                    // A late answer to something we've given up on
+                    _ => return None,
+                };
+                self.state = next;
+                self.since = SystemTime::now();
+                match next {
+                    Masterstate::LoginPassword => Some(
+                        hb::RPTLPacket { id }
+                            .password_response(&self.salt, &self.password)
+                            .to_vec(),
+                    ),
+                    Masterstate::Config => {
+                        Some(hb::RPTCPacket::create(id, info).construct().to_vec())
+                    }
+                    Masterstate::Options => Some(hb::RPTOPacket::construct(id, &self.peer.options)),
+                    _ => None,
+                }
+            }
+            hb::Packet::MstPong(_) => {
+                if self.connected() {
+                    self.peer.last_check = SystemTime::now();
+                    self.state = Masterstate::Connected;
+                }
+                None
+            }
+            hb::Packet::MstNak(_) => {
+                if self.active() {
+                    self.naks += 1;
+                    system.master_naks += 1;
+                    self.fail(Failure::Nak(self.state), timers, system);
+                }
+                None
+            }
+            hb::Packet::MstCl(_) => {
+                // We've received a disconnect request from the master.
+                if self.active() {
+                    self.closes += 1;
+                    system.master_closes += 1;
+                    self.retry = Duration::from_secs(timers.master_retry);
+                    self.state = Masterstate::Closed;
+                    self.since = SystemTime::now();
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    // We're going away, returns RPTCL if the master knows about us
+    pub fn close(&mut self) -> Option<Vec<u8>> {
+        let was = self.state;
+        self.state = Masterstate::Disable;
+        match was {
+            Masterstate::Disable | Masterstate::Logout | Masterstate::Closed => None,
+            _ => Some([hb::RPTCL, &self.peer.id.to_be_bytes()].concat()),
+        }
+    }
+
+    // Logged in or part way through logging in
+    fn active(&self) -> bool {
+        !matches!(
+            self.state,
+            Masterstate::Disable | Masterstate::Logout | Masterstate::Closed
+        )
+    }
+
+    pub fn connected(&self) -> bool {
+        matches!(
+            self.state,
+            Masterstate::Connected | Masterstate::WaitingPong
+        )
+    }
+
+    /* Take the address from the last lookup and start another. DNS can
+    stall, so it runs on its own thread and a failure keeps the last good
+    address. At most one lookup runs at a time. */
+    fn lookup(&mut self) {
+        match self.lookup.as_ref().map(Receiver::try_recv) {
+            Some(Err(TryRecvError::Empty)) => return,
+            Some(Ok(Some(ip))) => self.peer.ip = ip,
+            _ => {}
+        }
+        let (tx, rx) = mpsc::channel();
+        let (name, address) = (self.name.clone(), self.address.clone());
+        thread::spawn(move || {
+            let found = resolve(&address);
+            if found.is_none() {
+                warn!(master = name; "Unable to resolve {}", address);
+            }
+            let _ = tx.send(found);
+        });
+        self.lookup = Some(rx);
+    }
+
+    // Drop back to Logout and work out how long to wait before trying again
+    fn fail(&mut self, why: Failure, timers: &system::Timers, system: &mut system::System) {
+        self.attempt += 1;
+        self.failures += 1;
+        system.master_failures += 1;
+        self.last_failure = Some(why);
+        self.retry = backoff(self.attempt, timers);
+        self.state = Masterstate::Logout;
+        self.since = SystemTime::now();
+    }
+}
+
+/* Look up a master's host:port. IPv4 is preferred as that's what most
+masters and our default bind address use. */
+pub fn resolve(address: &str) -> Option<SocketAddr> {
+    let addrs: Vec<SocketAddr> = address.to_socket_addrs().ok()?.collect();
+    addrs
+        .iter()
+        .find(|a| a.is_ipv4())
+        .or_else(|| addrs.first())
+        .copied()
+}
+
+/* Double the wait on every failure up to master_retry, then pick somewhere
+between half and all of it so a restarted master isn't hit by everyone at once. */
+fn backoff(attempt: u32, timers: &system::Timers) -> Duration {
+    let secs = timers
+        .master_retry_min
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(timers.master_retry);
+    let ms = secs * 1000;
+    let mut r = [0; 4];
+    let jitter = match getrandom::getrandom(&mut r) {
+        Ok(()) => u32::from_be_bytes(r) as u64 % (ms / 2 + 1),
+        Err(_) => ms / 2,
+    };
+    Duration::from_millis(ms / 2 + jitter)
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Timeout(s) => write!(f, "no answer while in {:?}", s),
+            Failure::Nak(s) => write!(f, "refused with MSTNAK while in {:?}", s),
+            Failure::PongTimeout => write!(f, "no pong"),
+            Failure::Resolve => write!(f, "unable to resolve the address"),
+        }
+    }
 }
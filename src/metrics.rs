@@ -0,0 +1,237 @@
+use crate::{master::Master, peers::Peer, streams::Streams, system::System};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+/* metrics.rs
+    Counters for Prometheus, served as /metrics by the API. Most of what
+    we export is already kept elsewhere, this only adds the counts nothing
+    else needs. Labels are limited to peer IDs, master names, slots and
+    the talkgroups of streams in progress so the series stay bounded.
+*/
+
+// How to read a per-master counter
+type MasterValue = fn(&Master) -> usize;
+
+#[derive(Default)]
+pub struct Metrics {
+    // Packets received by opcode
+    packets: BTreeMap<&'static str, u64>,
+    // Packets we did nothing with, by reason
+    dropped: BTreeMap<&'static str, u64>,
+    // Repeater logins refused, by reason
+    login_failures: BTreeMap<&'static str, u64>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn packet(&mut self, opcode: &'static str) {
+        *self.packets.entry(opcode).or_default() += 1;
+    }
+
+    pub fn dropped(&mut self, reason: &'static str) {
+        *self.dropped.entry(reason).or_default() += 1;
+    }
+
+    pub fn login_failed(&mut self, reason: &'static str) {
+        *self.login_failures.entry(reason).or_default() += 1;
+    }
+
+    // Everything in the Prometheus text format
+    pub fn render(
+        &self,
+        mash: &HashMap<u32, Peer>,
+        masters: &[Master],
+        streams: &Streams,
+        system: &System,
+    ) -> String {
+        let mut out = String::new();
+        let uptime = system.uptime.elapsed().unwrap_or_default().as_secs();
+        metric(
+            &mut out,
+            "dmrpal_uptime_seconds",
+            "gauge",
+            "Seconds since dmrpal started",
+        );
+        sample(&mut out, "dmrpal_uptime_seconds", &[], uptime);
+
+        let mut peers: Vec<&Peer> = mash.values().collect();
+        peers.sort_by_key(|p| p.id);
+        metric(
+            &mut out,
+            "dmrpal_peers",
+            "gauge",
+            "Repeaters logged in or logging in",
+        );
+        sample(&mut out, "dmrpal_peers", &[], peers.len());
+        metric(
+            &mut out,
+            "dmrpal_peer_rx_bytes_total",
+            "counter",
+            "Bytes sent to a repeater",
+        );
+        for p in &peers {
+            let labels = [("peer", p.id.to_string()), ("callsign", p.callsign.clone())];
+            sample(&mut out, "dmrpal_peer_rx_bytes_total", &labels, p.rx_bytes);
+        }
+        metric(
+            &mut out,
+            "dmrpal_peer_tx_bytes_total",
+            "counter",
+            "Bytes received from a repeater",
+        );
+        for p in &peers {
+            let labels = [("peer", p.id.to_string()), ("callsign", p.callsign.clone())];
+            sample(&mut out, "dmrpal_peer_tx_bytes_total", &labels, p.tx_bytes);
+        }
+
+        metric(
+            &mut out,
+            "dmrpal_master_up",
+            "gauge",
+            "1 when logged in to the master",
+        );
+        for m in masters {
+            sample(
+                &mut out,
+                "dmrpal_master_up",
+                &[("master", m.name.clone())],
+                m.connected() as u8,
+            );
+        }
+        let per_master: [(&str, &str, MasterValue); 6] = [
+            (
+                "dmrpal_master_rx_bytes_total",
+                "Bytes sent to the master",
+                |m| m.peer.rx_bytes,
+            ),
+            (
+                "dmrpal_master_tx_bytes_total",
+                "Bytes received from the master",
+                |m| m.peer.tx_bytes,
+            ),
+            (
+                "dmrpal_master_reconnects_total",
+                "Times the master login was restarted",
+                |m| m.reconnects,
+            ),
+            (
+                "dmrpal_master_failures_total",
+                "Master logins that failed",
+                |m| m.failures,
+            ),
+            ("dmrpal_master_naks_total", "MSTNAKs from the master", |m| {
+                m.naks
+            }),
+            (
+                "dmrpal_master_closes_total",
+                "MSTCLs from the master",
+                |m| m.closes,
+            ),
+        ];
+        for (name, help, value) in per_master {
+            metric(&mut out, name, "counter", help);
+            for m in masters {
+                sample(&mut out, name, &[("master", m.name.clone())], value(m));
+            }
+        }
+
+        metric(&mut out, "dmrpal_streams_total", "counter", "Streams seen");
+        sample(&mut out, "dmrpal_streams_total", &[], streams.total);
+        let mut active: BTreeMap<(u8, u32), usize> = BTreeMap::new();
+        for s in streams.current_streams.values() {
+            *active.entry((s.slot, s.dst)).or_default() += 1;
+        }
+        metric(
+            &mut out,
+            "dmrpal_streams_active",
+            "gauge",
+            "Streams in progress by slot and talkgroup",
+        );
+        for ((slot, tg), n) in active {
+            let labels = [("slot", slot.to_string()), ("tg", tg.to_string())];
+            sample(&mut out, "dmrpal_streams_active", &labels, n);
+        }
+
+        let counters = [
+            (
+                "dmrpal_stream_timeouts_total",
+                "Streams cut off for running too long",
+                system.total_timeouts,
+            ),
+            (
+                "dmrpal_streams_blocked_total",
+                "Streams dropped by ingress rules",
+                system.streams_blocked,
+            ),
+            (
+                "dmrpal_acl_denied_total",
+                "Logins refused by the ACL",
+                system.acl_denied,
+            ),
+            (
+                "dmrpal_peer_closes_total",
+                "RPTCLs from repeaters",
+                system.peer_closes,
+            ),
+        ];
+        for (name, help, value) in counters {
+            metric(&mut out, name, "counter", help);
+            sample(&mut out, name, &[], value);
+        }
+
+        let labelled = [
+            (
+                "dmrpal_packets_received_total",
+                "Packets received by opcode",
+                "opcode",
+                &self.packets,
+            ),
+            (
+                "dmrpal_packets_dropped_total",
+                "Packets not acted on by reason",
+                "reason",
+                &self.dropped,
+            ),
+            (
+                "dmrpal_login_failures_total",
+                "Repeater logins refused by reason",
+                "reason",
+                &self.login_failures,
+            ),
+        ];
+        for (name, help, label, values) in labelled {
+            metric(&mut out, name, "counter", help);
+            for (k, v) in values {
+                sample(&mut out, name, &[(label, k.to_string())], *v);
+            }
+        }
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, String)], value: impl std::fmt::Display) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect();
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
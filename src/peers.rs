@@ -1,14 +1,88 @@
 use crate::{
-    echo, slot,
+    debug, echo, hb, slot, system,
     talkgroups::{Talkgroup, TgActivate},
+    warn,
 };
+use serde_derive::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
+    fmt,
     net::{IpAddr, Ipv4Addr},
     string,
     time::SystemTime,
 };
 
+/* Access control for repeaters logging in, from the [acl] config section.
+Deny entries win over allow entries, an ID on neither list gets the default
+policy. Peers listed in `sources` may only log in from those networks. */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Acl {
+    pub default: Policy,
+    pub allow: Vec<IdRange>,
+    pub deny: Vec<IdRange>,
+    pub sources: Vec<SourceRule>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    Allow,
+    Deny,
+}
+
+// A single ID or an inclusive range, written as 2345601 or "2340000-2349999"
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(try_from = "IdSpec", into = "IdSpec")]
+pub struct IdRange {
+    pub from: u32,
+    pub to: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum IdSpec {
+    Id(u32),
+    Text(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SourceRule {
+    pub id: IdRange,
+    pub networks: Vec<Network>,
+}
+
+// An address with an optional prefix length, "192.168.1.0/24" or "10.0.0.1"
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Network {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+// Why a peer was refused
+#[derive(Debug, PartialEq)]
+pub enum AclDeny {
+    InvalidId,
+    Denied(IdRange),
+    NotAllowed,
+    Source(IpAddr),
+}
+
+/* Where a repeater is in the login sequence. Each packet is only
+accepted in the state that comes before it:
+RPTL -> LoginRequest, RPTK -> Authenticated, RPTC -> Configured,
+RPTO -> Options, first RPTPING -> Connected */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Peerstate {
+    LoginRequest,
+    Authenticated,
+    Configured,
+    Options,
+    Connected,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Peertype {
     Local,
@@ -19,13 +93,18 @@ pub enum Peertype {
 pub struct Peer {
     pub id: u32,
     pub callsign: String,
+    pub color_code: u8,
+    pub description: String,
     pub duplex: u8,
     pub echo: echo::Queue,
     pub enabled: bool,
-    pub frequency: String,
+    pub rx_freq: u32,
+    pub tx_freq: u32,
     pub software: String,
+    pub package: String,
     pub latitude: f32,
     pub last_check: SystemTime,
+    pub location: String,
     pub longitude: f32,
     pub power: u16,
     pub height: u16,
@@ -35,8 +114,187 @@ pub struct Peer {
     pub options: String,
     pub peer_type: Peertype,
     pub rx_bytes: usize,
+    pub salt: [u8; 4],
     pub slot: slot::Slot,
+    pub state: Peerstate,
     pub tg_expire: u64,
+    pub url: String,
+    // Last RPTS report, when we last asked for a beacon and how often it has asked us
+    pub status: Option<Status>,
+    pub beacon: Option<SystemTime>,
+    pub beacon_requests: usize,
+}
+
+// A status report sent with RPTS, the payload is free form
+pub struct Status {
+    pub data: Vec<u8>,
+    pub time: SystemTime,
+}
+
+impl Status {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            time: SystemTime::now(),
+        }
+    }
+
+    // The printable part of the report
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.data)
+            .chars()
+            .filter(|c| !c.is_control())
+            .collect::<String>()
+            .trim()
+            .to_owned()
+    }
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self {
+            default: Policy::Allow,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
+}
+
+impl Acl {
+    pub fn check(&self, id: u32, ip: IpAddr) -> Result<(), AclDeny> {
+        // Zero is never a valid repeater ID
+        if id == 0 {
+            return Err(AclDeny::InvalidId);
+        }
+
+        if let Some(r) = self.deny.iter().find(|r| r.contains(id)) {
+            return Err(AclDeny::Denied(*r));
+        }
+
+        if !self.allow.iter().any(|r| r.contains(id)) && self.default == Policy::Deny {
+            return Err(AclDeny::NotAllowed);
+        }
+
+        let mut rules = self.sources.iter().filter(|s| s.id.contains(id)).peekable();
+        if rules.peek().is_some() && !rules.any(|s| s.networks.iter().any(|n| n.contains(ip))) {
+            return Err(AclDeny::Source(ip));
+        }
+        Ok(())
+    }
+}
+
+impl IdRange {
+    pub fn contains(&self, id: u32) -> bool {
+        id >= self.from && id <= self.to
+    }
+}
+
+impl TryFrom<IdSpec> for IdRange {
+    type Error = String;
+
+    fn try_from(spec: IdSpec) -> Result<Self, Self::Error> {
+        let text = match spec {
+            IdSpec::Id(id) => return Ok(Self { from: id, to: id }),
+            IdSpec::Text(t) => t,
+        };
+        let (from, to) = match text.split_once('-') {
+            Some((f, t)) => (f.trim(), t.trim()),
+            None => (text.trim(), text.trim()),
+        };
+        match (from.parse(), to.parse()) {
+            (Ok(from), Ok(to)) if from <= to => Ok(Self { from, to }),
+            _ => Err(format!("invalid ID range: {}", text)),
+        }
+    }
+}
+
+impl From<IdRange> for IdSpec {
+    fn from(r: IdRange) -> Self {
+        if r.from == r.to {
+            IdSpec::Id(r.from)
+        } else {
+            IdSpec::Text(format!("{}-{}", r.from, r.to))
+        }
+    }
+}
+
+impl fmt::Display for IdRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.from == self.to {
+            write!(f, "{}", self.from)
+        } else {
+            write!(f, "{}-{}", self.from, self.to)
+        }
+    }
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(n), IpAddr::V4(a)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(n) & mask == u32::from(a) & mask
+            }
+            (IpAddr::V6(n), IpAddr::V6(a)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(n) & mask == u128::from(a) & mask
+            }
+            // A dual stack socket sees IPv4 peers as ::ffff:a.b.c.d
+            (IpAddr::V6(_), IpAddr::V4(a)) => self.contains(IpAddr::V6(a.to_ipv6_mapped())),
+            (IpAddr::V4(_), IpAddr::V6(a)) => match a.to_ipv4_mapped() {
+                Some(a) => self.contains(IpAddr::V4(a)),
+                None => false,
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (text.as_str(), None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid network address: {}", text))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => match p.trim().parse::<u8>() {
+                Ok(p) if p <= max => p,
+                _ => return Err(format!("invalid network prefix: {}", text)),
+            },
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl From<Network> for String {
+    fn from(n: Network) -> Self {
+        format!("{}/{}", n.addr, n.prefix)
+    }
+}
+
+impl fmt::Display for AclDeny {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AclDeny::InvalidId => write!(f, "invalid ID"),
+            AclDeny::Denied(r) => write!(f, "matches deny rule {}", r),
+            AclDeny::NotAllowed => write!(f, "not on the allow list"),
+            AclDeny::Source(ip) => write!(f, "source address {} not permitted", ip),
+        }
+    }
+}
+
+impl Default for Peer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Peer {
@@ -44,48 +302,90 @@ impl Peer {
         Self {
             id: 0,
             callsign: string::String::default(),
+            color_code: 0,
+            description: string::String::default(),
             duplex: 0,
             echo: echo::Queue::default(),
             enabled: false,
-            frequency: string::String::default(),
+            rx_freq: 0,
+            tx_freq: 0,
             software: string::String::default(),
+            package: string::String::default(),
             latitude: 0.0,
             last_check: SystemTime::now(),
+            location: string::String::default(),
             longitude: 0.0,
             power: 0,
             height: 0,
             ip: std::net::SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
-            talk_groups: HashMap::from([
-                (0, Talkgroup::default()),
-                (31337, Talkgroup::set(2, TgActivate::Static(31337), None)),
-                (2351, Talkgroup::set(1, TgActivate::Static(2351), None)),
-                (235, Talkgroup::set(1, TgActivate::Static(235), None)),
-                (844, Talkgroup::set(2, TgActivate::Static(844), None)),
-                (840, Talkgroup::set(2, TgActivate::Static(840), None)),
-                (123, Talkgroup::set(1, TgActivate::Static(123), None)),
-                (113, Talkgroup::set(1, TgActivate::Static(113), None)),
-                (3, Talkgroup::set(1, TgActivate::Static(3), None)),
-                (2, Talkgroup::set(1, TgActivate::Static(2), None)),
-                (1, Talkgroup::set(1, TgActivate::Static(1), None)),
-            ]),
+            talk_groups: HashMap::from([(0, Talkgroup::default())]),
             tx_bytes: 0,
             options: string::String::default(),
             peer_type: Peertype::Local,
             rx_bytes: 0,
+            salt: [0; 4],
             slot: slot::Slot::init(),
+            state: Peerstate::LoginRequest,
             tg_expire: 15,
+            url: string::String::default(),
+            status: None,
+            beacon: None,
+            beacon_requests: 0,
         }
     }
 
-    // Check if the peer is allowed to sign in.
-    pub fn acl(&self) -> bool {
-        let known_peers = vec![000000];
-        for k in known_peers {
-            if self.id.eq(&k) {
-                return false;
-            }
+    // A repeater has sent RPTL, give it a fresh challenge salt and the configured defaults
+    pub fn login(
+        id: u32,
+        ip: std::net::SocketAddr,
+        config: &system::Config,
+    ) -> Result<Self, getrandom::Error> {
+        let mut peer = Self::new();
+        getrandom::getrandom(&mut peer.salt)?;
+        peer.id = id;
+        peer.ip = ip;
+        peer.set_talkgroups(&config.peers.talkgroups);
+        peer.slot.hang = config.timers.slot_hang;
+        peer.tg_expire = config.peers.ua_expire;
+        Ok(peer)
+    }
+
+    // Add static talkgroups from the config
+    pub fn set_talkgroups(&mut self, tgs: &[system::StaticTg]) {
+        for tg in tgs {
+            self.talk_groups.insert(
+                tg.id,
+                Talkgroup::set(tg.slot, TgActivate::Static(tg.id), None),
+            );
         }
-        true
+    }
+
+    // Is this packet from the address that started the login, and in the state we expect?
+    pub fn expect(&self, ip: std::net::SocketAddr, states: &[Peerstate]) -> bool {
+        self.ip == ip && states.contains(&self.state)
+    }
+
+    // Check if the peer is allowed to sign in.
+    pub fn acl(&self, acl: &Acl) -> Result<(), AclDeny> {
+        acl.check(self.id, self.ip.ip())
+    }
+
+    // Take the repeater details from an RPTC packet
+    pub fn config(&mut self, rptc: &hb::RPTCPacket) {
+        self.callsign = rptc.callsign();
+        self.rx_freq = rptc.rx_freq();
+        self.tx_freq = rptc.tx_freq();
+        self.power = rptc.power();
+        self.color_code = rptc.color_code();
+        self.latitude = rptc.latitude();
+        self.longitude = rptc.longitude();
+        self.height = rptc.height();
+        self.location = rptc.location();
+        self.description = rptc.description();
+        self.duplex = rptc.slots();
+        self.url = rptc.url();
+        self.software = rptc.software_id();
+        self.package = rptc.package_id();
     }
 
     pub fn echo(&mut self, data: [u8; 55], stream: u32) {
@@ -122,55 +422,94 @@ impl Peer {
                 }
             }
             _ => {
-                eprintln!("Can't lock slot, invalid slot number!");
+                warn!(peer_id = self.id, slot = sl; "Can't lock slot, invalid slot number");
                 return true;
             }
         }
         false
     }
 
+    // Undo a lock, on both slots for a simplex peer as lock() takes both
+    pub fn release(&mut self, dst: u32, sl: u8) {
+        if sl == 1 || self.duplex == 4 {
+            self.slot.release(slot::Slots::One(dst));
+        }
+        if sl == 2 || self.duplex == 4 {
+            self.slot.release(slot::Slots::Two(dst));
+        }
+    }
+
+    /* Options look like TS1_1=91;TS2_1=840;UAT=15, the number after TS
+    is the slot. Anything we can't read is skipped. */
     pub fn options(&mut self) {
-        for opts in self.options.split(';') {
-            if opts.len() < 3 {
+        for opt in self.options.split(';') {
+            let Some((key, value)) = opt.split_once('=') else {
                 continue;
-            }
-            match &opts[..3] {
-                "TS1" | "TS2" => match opts.chars().nth(2) {
-                    Some(s) => {
-                        let slot = s as u8 - 48;
-                        let mut tg: u32 = 0;
-                        for i in opts[5..].bytes() {
-                            if i > 47 && i < 58 {
-                                tg = tg * 10;
-                                tg = tg + i as u32 - 48;
-                            }
-                        }
-                        self.talk_groups
-                            .insert(tg, Talkgroup::set(slot, TgActivate::Static(tg), None));
-                        println!("OPTIONS {}, Added {} {}", self.id, slot, tg);
-                    }
-                    None => continue,
-                },
-                "UAT" => {
-                    let mut t: u64 = 0;
-                    for i in opts[4..].bytes() {
-                        if i > 47 && i < 58 {
-                            t = t * 10;
-                            t = t + i as u64 - 48;
-                        }
-                    }
-                    self.tg_expire = t;
+            };
+            let Ok(value) = value.trim().parse::<u32>() else {
+                debug!(peer_id = self.id; "Skipping option {:?}", opt);
+                continue;
+            };
+            match key.trim().get(..3) {
+                Some(ts @ ("TS1" | "TS2")) if value != 0 => {
+                    let slot = if ts == "TS1" { 1 } else { 2 };
+                    self.talk_groups
+                        .insert(value, Talkgroup::set(slot, TgActivate::Static(value), None));
+                    debug!(peer_id = self.id, tg = value, slot = slot; "Static talkgroup from options");
                 }
-                _ => continue,
+                Some("UAT") => self.tg_expire = value as u64,
+                _ => debug!(peer_id = self.id; "Skipping option {:?}", opt),
             }
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(text: &str) -> Peer {
+        let mut p = Peer::new();
+        p.options = text.to_owned();
+        p.options();
+        p
+    }
+
+    #[test]
+    fn options_set_talkgroups_and_expiry() {
+        let p = options("TS1_1=91;TS2_1=840;UAT=30");
+        assert_eq!(p.talk_groups[&91].sl, 1);
+        assert_eq!(p.talk_groups[&840].sl, 2);
+        assert_eq!(p.tg_expire, 30);
+    }
+
+    // These used to panic slicing the option text
+    #[test]
+    fn malformed_options_are_skipped() {
+        for bad in [
+            "UAT",
+            "TS1",
+            "TS1_",
+            "TS1_1=",
+            "UAT=x",
+            "\u{e9}\u{e9}=1",
+            "TS2_1=99999999999",
+            ";;=;",
+        ] {
+            let p = options(bad);
+            assert_eq!(p.talk_groups.len(), 1, "{}", bad);
+            assert_eq!(p.tg_expire, 15, "{}", bad);
+        }
+    }
 
-    // Set the peer ID
-    pub fn pid(&mut self, buff: &[u8; 4]) {
-        self.id = ((buff[0] as u32) << 24)
-            | ((buff[1] as u32) << 16)
-            | ((buff[2] as u32) << 8)
-            | (buff[3] as u32);
+    #[test]
+    fn network_matches_mapped_addresses() {
+        let v4 = Network::try_from("192.0.2.0/24".to_owned()).unwrap();
+        assert!(v4.contains("192.0.2.7".parse().unwrap()));
+        assert!(v4.contains("::ffff:192.0.2.7".parse().unwrap()));
+        assert!(!v4.contains("::ffff:198.51.100.7".parse().unwrap()));
+        assert!(!v4.contains("2001:db8::7".parse().unwrap()));
+        let v6 = Network::try_from("::ffff:192.0.2.0/120".to_owned()).unwrap();
+        assert!(v6.contains("192.0.2.7".parse().unwrap()));
     }
 }
@@ -0,0 +1,684 @@
+use crate::{
+    echo,
+    hb::{self, DMRAPacket, DMRDPacket},
+    master::Master,
+    peers::Peer,
+    rules::{Block, Direction, Rules, Scope},
+    streams::Streams,
+    talkgroups::{Talkgroup, TgActivate},
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::SystemTime,
+};
+
+/* router.rs
+    Decide where a DMRD packet goes. The router updates slot locks,
+    talkgroup subscriptions and echo queues in the peer table, but never
+    touches the socket; it hands back a list of actions for the caller.
+*/
+
+pub struct Router<'a> {
+    pub disconnect_tg: u32,
+    pub echo_tg: u32,
+    pub echo_slot: u8,
+    // Seconds to wait after a recording ends before playing it back
+    pub echo_delay: u64,
+    pub rules: &'a Rules,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Send {
+        to: Scope,
+        addr: SocketAddr,
+        data: [u8; 55],
+    },
+    SendAlias {
+        to: Scope,
+        addr: SocketAddr,
+        data: [u8; hb::DMRA_LEN],
+    },
+    // The first frame of a stream that passed the ingress rules
+    Started {
+        stream: u32,
+        peer: u32,
+        src: u32,
+        dst: u32,
+        slot: u8,
+        private: bool,
+    },
+    // A stream's talker alias is complete
+    Alias {
+        stream: u32,
+        src: u32,
+        alias: String,
+    },
+    Dropped(Dropped),
+    Subscribe {
+        peer: u32,
+        tg: u32,
+        slot: u8,
+    },
+    // The UA talkgroups the disconnect talkgroup removed
+    ClearUa {
+        peer: u32,
+        tgs: Vec<u32>,
+    },
+    Echo {
+        peer: u32,
+    },
+}
+
+// Why a packet wasn't routed. Blocked and timed out streams are only reported for the first frame.
+#[derive(Debug, PartialEq)]
+pub enum Dropped {
+    Unknown,
+    Timeout,
+    // The rest of a stream that already timed out
+    TimedOut,
+    Ingress(Scope, Block),
+    // The rest of a stream already dropped by an ingress rule
+    Blocked,
+    Egress(Scope, Block),
+}
+
+impl Router<'_> {
+    pub fn route(
+        &self,
+        hbp: &DMRDPacket,
+        src: SocketAddr,
+        peers: &mut HashMap<u32, Peer>,
+        masters: &mut [Master],
+        streams: &mut Streams,
+    ) -> Vec<Action> {
+        let mut actions = Vec::new();
+
+        // Check to see if the sending peer or master is enabled
+        let from_master = masters
+            .iter()
+            .position(|m| m.connected() && m.peer.ip == src);
+        let known = match peers.get(&hbp.rpt) {
+            Some(p) => p.enabled && p.ip == src,
+            None => false,
+        };
+        if !known && from_master.is_none() {
+            actions.push(Action::Dropped(Dropped::Unknown));
+            return actions;
+        }
+
+        // Masters may use different talkgroup numbers to us
+        let mut hbp = hbp.clone();
+        let from = match from_master {
+            Some(i) => {
+                hbp.dst = masters[i].local_tg(hbp.dst);
+                Scope::Master(i)
+            }
+            None => Scope::Peer(hbp.rpt),
+        };
+
+        let (new, timed_out) = match streams.current_streams.get(&hbp.si) {
+            Some(s) => (false, s.time_out),
+            None => (true, false),
+        };
+        if streams.stream(&hbp) {
+            actions.push(Action::Dropped(match timed_out {
+                true => Dropped::TimedOut,
+                false => Dropped::Timeout,
+            }));
+            return actions;
+        }
+
+        // Subscriber and talkgroup rules for traffic coming in
+        let group = hbp.ct == hb::CT_GROUP;
+        if streams.is_blocked(hbp.si) {
+            actions.push(Action::Dropped(Dropped::Blocked));
+            return actions;
+        }
+        let allowed = self
+            .rules
+            .check(Direction::Ingress, from, hbp.src, hbp.dst, group)
+            .and_then(|_| match from_master {
+                Some(i) => masters[i].check(Direction::Ingress, hbp.src, hbp.dst, group),
+                None => Ok(()),
+            });
+        if let Err(e) = allowed {
+            if streams.block(hbp.si) {
+                actions.push(Action::Dropped(Dropped::Ingress(from, e)));
+            }
+            return actions;
+        }
+        if new {
+            actions.push(Action::Started {
+                stream: hbp.si,
+                peer: hbp.rpt,
+                src: hbp.src,
+                dst: hbp.dst,
+                slot: hbp.sl,
+                private: !group,
+            });
+        }
+
+        let data = hbp.construct();
+
+        // Repeat to peers who are members of the same talkgroup and peer type.
+        for p in peers.values_mut() {
+            // Only repeat to peers which are enabled
+            if !p.enabled {
+                continue;
+            }
+            if p.talk_groups.contains_key(&hbp.dst) {
+                // Subscriber and talkgroup rules for traffic going out
+                if p.ip != src {
+                    let to = Scope::Peer(p.id);
+                    if let Err(e) = self
+                        .rules
+                        .check(Direction::Egress, to, hbp.src, hbp.dst, group)
+                    {
+                        if streams.block_peer(hbp.si, p.id) {
+                            actions.push(Action::Dropped(Dropped::Egress(to, e)));
+                        }
+                        continue;
+                    }
+                }
+                if let Some(tx) = self.forward(p, &hbp, src, data) {
+                    streams.sent(hbp.si, Scope::Peer(p.id));
+                    actions.push(Action::Send {
+                        to: Scope::Peer(p.id),
+                        addr: p.ip,
+                        data: tx,
+                    });
+                }
+            } else if p.ip == src && hbp.dst != self.disconnect_tg {
+                // If no talkgroup is found for the peer then we subscribe the peer to the talkgroup requested.
+                // If the peer does not request this talkgroup again in a 15 minute window the peer is auto-
+                // matically unsubscribed.
+                p.talk_groups.insert(
+                    hbp.dst,
+                    Talkgroup::set(hbp.sl, TgActivate::Ua(hbp.dst), Some(p.tg_expire)),
+                );
+                actions.push(Action::Subscribe {
+                    peer: p.id,
+                    tg: hbp.dst,
+                    slot: hbp.sl,
+                });
+            } else if p.ip == src && new {
+                // The disconnect talkgroup drops all the sender's UA talkgroups, once per call
+                let mut tgs = Vec::new();
+                p.talk_groups.retain(|id, t| {
+                    if t.ua {
+                        tgs.push(*id);
+                    }
+                    !t.ua
+                });
+                if !tgs.is_empty() {
+                    actions.push(Action::ClearUa { peer: p.id, tgs });
+                }
+            }
+
+            if hbp.dst == self.echo_tg && hbp.sl == self.echo_slot && p.id == hbp.rpt {
+                p.echo(data, hbp.si);
+                actions.push(Action::Echo { peer: p.id });
+            }
+        }
+
+        // Then to any masters carrying the talkgroup
+        for (i, m) in masters.iter_mut().enumerate() {
+            if !m.connected() || m.peer.ip == src || !m.peer.talk_groups.contains_key(&hbp.dst) {
+                continue;
+            }
+            let to = Scope::Master(i);
+            let allowed = self
+                .rules
+                .check(Direction::Egress, to, hbp.src, hbp.dst, group)
+                .and_then(|_| m.check(Direction::Egress, hbp.src, hbp.dst, group));
+            if let Err(e) = allowed {
+                if streams.block_peer(hbp.si, m.peer.id) {
+                    actions.push(Action::Dropped(Dropped::Egress(to, e)));
+                }
+                continue;
+            }
+            if let Some(mut tx) = self.forward(&mut m.peer, &hbp, src, data) {
+                // Masters see our ID as the repeater and their own talkgroup numbers
+                tx[8..11].copy_from_slice(&m.remote_tg(hbp.dst).to_be_bytes()[1..]);
+                tx[11..15].copy_from_slice(&m.peer.id.to_be_bytes());
+                streams.sent(hbp.si, to);
+                actions.push(Action::Send {
+                    to,
+                    addr: m.peer.ip,
+                    data: tx,
+                });
+            }
+        }
+        actions
+    }
+
+    /* Talker alias blocks follow their stream to everyone it was sent to,
+    masters see our ID as the repeater as they do for DMRD. */
+    pub fn alias(
+        &self,
+        dmra: &DMRAPacket,
+        src: SocketAddr,
+        peers: &HashMap<u32, Peer>,
+        masters: &[Master],
+        streams: &mut Streams,
+    ) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let known = match peers.get(&dmra.id) {
+            Some(p) => p.enabled && p.ip == src,
+            None => masters.iter().any(|m| m.connected() && m.peer.ip == src),
+        };
+        if !known {
+            actions.push(Action::Dropped(Dropped::Unknown));
+            return actions;
+        }
+        let stream = match streams.find(dmra.id, dmra.src) {
+            Some(s) => s,
+            None => return actions,
+        };
+        if stream.alias.add(dmra.block, &dmra.data) {
+            actions.push(Action::Alias {
+                stream: stream.id,
+                src: dmra.src,
+                alias: stream.alias.text.clone().unwrap_or_default(),
+            });
+        }
+        for to in &stream.receivers {
+            let (addr, id) = match to {
+                Scope::Peer(id) => match peers.get(id) {
+                    Some(p) => (p.ip, dmra.id),
+                    None => continue,
+                },
+                Scope::Master(i) => match masters.get(*i) {
+                    Some(m) => (m.peer.ip, m.peer.id),
+                    None => continue,
+                },
+            };
+            let tx = DMRAPacket { id, ..dmra.clone() };
+            actions.push(Action::SendAlias {
+                to: *to,
+                addr,
+                data: tx.construct(),
+            });
+        }
+        actions
+    }
+
+    /* Check we can lock the slot on a destination carrying the talkgroup. If the
+    destination is simplex check if either slot is locked. Returns the frame to send. */
+    fn forward(
+        &self,
+        p: &mut Peer,
+        hbp: &DMRDPacket,
+        src: SocketAddr,
+        data: [u8; 55],
+    ) -> Option<[u8; 55]> {
+        let unset = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+        if p.lock(hbp.dst, hbp.sl) {
+            return None;
+        }
+        let tg = p.talk_groups.get_mut(&hbp.dst)?;
+        if tg.sl == hbp.sl && p.ip != src && p.ip != unset {
+            tg.la = SystemTime::now();
+            Some(data)
+        } else {
+            if tg.ua {
+                // Reset the time stamp for the UA talkgroup
+                tg.time_stamp = SystemTime::now();
+            }
+            None
+        }
+    }
+
+    /* If a peer has an echo Queue to play then send it back. The queue is only played after 5 seconds has passed since the user recorded the message.
+    Only when the queue has been played do we then drop the queue by replacing with the default.
+     */
+    pub fn echo(&self, peers: &mut HashMap<u32, Peer>) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for p in peers.values_mut() {
+            if !p.echo.has_items() {
+                if let Ok(t) = p.echo.la_time.elapsed() {
+                    if t.as_secs() >= self.echo_delay {
+                        if p.lock(p.id, self.echo_slot) {
+                            continue;
+                        }
+                        for i in &p.echo.echos {
+                            actions.push(Action::Send {
+                                to: Scope::Peer(p.id),
+                                addr: p.ip,
+                                data: i.data,
+                            });
+                        }
+                        p.echo = echo::Queue::default();
+                    }
+                }
+            }
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        master::Masterstate,
+        peers::{IdRange, Peerstate},
+        system::{MasterConfig, MasterTg, Timers},
+    };
+
+    const DISCONNECT: u32 = 4000;
+    const ECHO: u32 = 9990;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    // A logged in repeater at 192.0.2.1:<id> with static talkgroups (tg, slot)
+    fn peer(id: u32, tgs: &[(u32, u8)]) -> Peer {
+        let mut p = Peer::new();
+        p.id = id;
+        p.ip = addr(id as u16);
+        p.enabled = true;
+        p.state = Peerstate::Connected;
+        p.duplex = 1;
+        for &(tg, slot) in tgs {
+            p.talk_groups
+                .insert(tg, Talkgroup::set(slot, TgActivate::Static(tg), None));
+        }
+        p
+    }
+
+    // Repeaters 1 and 2 carry TG 91 on TS1, repeater 3 has nothing
+    fn peers() -> HashMap<u32, Peer> {
+        [peer(1, &[(91, 1)]), peer(2, &[(91, 1)]), peer(3, &[])]
+            .into_iter()
+            .map(|p| (p.id, p))
+            .collect()
+    }
+
+    // A connected master knowing our TG 91 as 2350
+    fn master() -> Master {
+        let c = MasterConfig {
+            name: "upstream".to_owned(),
+            id: 234500199,
+            address: "192.0.2.9:62031".to_owned(),
+            talkgroups: vec![MasterTg {
+                id: 91,
+                slot: 1,
+                remote: Some(2350),
+            }],
+            ..Default::default()
+        };
+        let mut m = Master::new(&c, &Timers::default());
+        m.state = Masterstate::Connected;
+        m
+    }
+
+    fn group(rpt: u32, dst: u32, slot: u8, si: u32) -> DMRDPacket {
+        DMRDPacket {
+            seq: 0,
+            src: 2345001,
+            dst,
+            rpt,
+            sl: slot,
+            ct: hb::CT_GROUP,
+            ft: 0,
+            dt: 0,
+            si,
+            dd: [0; 35],
+        }
+    }
+
+    fn route(
+        rules: &Rules,
+        hbp: &DMRDPacket,
+        src: SocketAddr,
+        peers: &mut HashMap<u32, Peer>,
+        masters: &mut [Master],
+        streams: &mut Streams,
+    ) -> Vec<Action> {
+        let router = Router {
+            disconnect_tg: DISCONNECT,
+            echo_tg: ECHO,
+            echo_slot: 2,
+            echo_delay: 5,
+            rules,
+        };
+        router.route(hbp, src, peers, masters, streams)
+    }
+
+    // Where the sends went
+    fn sent(actions: &[Action]) -> Vec<(Scope, [u8; 55])> {
+        actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::Send { to, data, .. } => Some((*to, *data)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fans_out_to_peers_on_the_talkgroup() {
+        let (rules, mut peers, mut streams) = (Rules::default(), peers(), Streams::init(180, 5));
+        let hbp = group(1, 91, 1, 0x1001);
+        let actions = route(&rules, &hbp, addr(1), &mut peers, &mut [], &mut streams);
+        assert!(actions.contains(&Action::Started {
+            stream: 0x1001,
+            peer: 1,
+            src: 2345001,
+            dst: 91,
+            slot: 1,
+            private: false,
+        }));
+        let sent = sent(&actions);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, Scope::Peer(2));
+        assert_eq!(sent[0].1, hbp.construct());
+    }
+
+    #[test]
+    fn unknown_sender_is_dropped() {
+        let (rules, mut peers, mut streams) = (Rules::default(), peers(), Streams::init(180, 5));
+        let hbp = group(1, 91, 1, 0x1001);
+        let actions = route(&rules, &hbp, addr(9), &mut peers, &mut [], &mut streams);
+        assert_eq!(actions, vec![Action::Dropped(Dropped::Unknown)]);
+    }
+
+    #[test]
+    fn timeout_is_reported_once() {
+        let (rules, mut peers, mut streams) = (Rules::default(), peers(), Streams::init(0, 5));
+        let hbp = group(1, 91, 1, 0x1001);
+        let actions = route(&rules, &hbp, addr(1), &mut peers, &mut [], &mut streams);
+        assert_eq!(sent(&actions).len(), 1);
+        let actions = route(&rules, &hbp, addr(1), &mut peers, &mut [], &mut streams);
+        assert_eq!(actions, vec![Action::Dropped(Dropped::Timeout)]);
+        let actions = route(&rules, &hbp, addr(1), &mut peers, &mut [], &mut streams);
+        assert_eq!(actions, vec![Action::Dropped(Dropped::TimedOut)]);
+    }
+
+    #[test]
+    fn locked_slot_refuses_a_second_talkgroup() {
+        let (rules, mut peers, mut streams) = (Rules::default(), peers(), Streams::init(180, 5));
+        peers.insert(2, peer(2, &[(91, 1), (92, 1)]));
+        peers.insert(3, peer(3, &[(92, 1)]));
+        let first = group(1, 91, 1, 0x1001);
+        let actions = route(&rules, &first, addr(1), &mut peers, &mut [], &mut streams);
+        assert_eq!(sent(&actions).len(), 1);
+
+        // TS1 on repeater 2 is busy with 91
+        let second = group(3, 92, 1, 0x1002);
+        let actions = route(&rules, &second, addr(3), &mut peers, &mut [], &mut streams);
+        assert!(sent(&actions).is_empty());
+
+        // The call on 91 carries on
+        let actions = route(&rules, &first, addr(1), &mut peers, &mut [], &mut streams);
+        assert_eq!(sent(&actions).len(), 1);
+    }
+
+    #[test]
+    fn keying_up_subscribes_the_sender() {
+        let (rules, mut peers, mut streams) = (Rules::default(), peers(), Streams::init(180, 5));
+        let hbp = group(3, 3100, 2, 0x1001);
+        let actions = route(&rules, &hbp, addr(3), &mut peers, &mut [], &mut streams);
+        assert!(actions.contains(&Action::Subscribe {
+            peer: 3,
+            tg: 3100,
+            slot: 2,
+        }));
+        let tg = &peers[&3].talk_groups[&3100];
+        assert!(tg.ua);
+        assert_eq!(tg.sl, 2);
+        // Nobody else asked for it
+        assert!(!peers[&1].talk_groups.contains_key(&3100));
+    }
+
+    #[test]
+    fn disconnect_clears_the_senders_ua_talkgroups_once() {
+        let (rules, mut peers, mut streams) = (Rules::default(), peers(), Streams::init(180, 5));
+        let ua = group(1, 3100, 2, 0x1001);
+        route(&rules, &ua, addr(1), &mut peers, &mut [], &mut streams);
+        assert!(peers[&1].talk_groups[&3100].ua);
+        let ua = group(3, 3100, 2, 0x1002);
+        route(&rules, &ua, addr(3), &mut peers, &mut [], &mut streams);
+
+        let disconnect = group(1, DISCONNECT, 2, 0x1003);
+        let actions = route(
+            &rules,
+            &disconnect,
+            addr(1),
+            &mut peers,
+            &mut [],
+            &mut streams,
+        );
+        let cleared: Vec<&Action> = actions
+            .iter()
+            .filter(|a| matches!(a, Action::ClearUa { .. }))
+            .collect();
+        assert_eq!(
+            cleared,
+            vec![&Action::ClearUa {
+                peer: 1,
+                tgs: vec![3100],
+            }]
+        );
+        assert!(!peers[&1].talk_groups.contains_key(&3100));
+        // Statics stay, other repeaters keep theirs
+        assert!(peers[&1].talk_groups.contains_key(&91));
+        assert!(peers[&3].talk_groups.contains_key(&3100));
+
+        // The rest of the call, and a second call with nothing left to clear, say nothing
+        let actions = route(
+            &rules,
+            &disconnect,
+            addr(1),
+            &mut peers,
+            &mut [],
+            &mut streams,
+        );
+        assert!(!actions.iter().any(|a| matches!(a, Action::ClearUa { .. })));
+        let again = group(1, DISCONNECT, 2, 0x1004);
+        let actions = route(&rules, &again, addr(1), &mut peers, &mut [], &mut streams);
+        assert!(!actions.iter().any(|a| matches!(a, Action::ClearUa { .. })));
+    }
+
+    #[test]
+    fn echo_talkgroup_records_the_call() {
+        let (rules, mut peers, mut streams) = (Rules::default(), peers(), Streams::init(180, 5));
+        let hbp = group(1, ECHO, 2, 0x1001);
+        let actions = route(&rules, &hbp, addr(1), &mut peers, &mut [], &mut streams);
+        assert!(actions.contains(&Action::Echo { peer: 1 }));
+        assert_eq!(peers[&1].echo.echos.len(), 1);
+        assert!(peers[&2].echo.echos.is_empty());
+
+        // Echo on the wrong slot is an ordinary talkgroup
+        let hbp = group(2, ECHO, 1, 0x1002);
+        let actions = route(&rules, &hbp, addr(2), &mut peers, &mut [], &mut streams);
+        assert!(!actions.contains(&Action::Echo { peer: 2 }));
+    }
+
+    #[test]
+    fn master_sees_its_own_talkgroup_and_our_id() {
+        let (rules, mut peers, mut streams) = (Rules::default(), peers(), Streams::init(180, 5));
+        let mut masters = [master()];
+        let hbp = group(1, 91, 1, 0x1001);
+        let actions = route(
+            &rules,
+            &hbp,
+            addr(1),
+            &mut peers,
+            &mut masters,
+            &mut streams,
+        );
+        let (_, data) = sent(&actions)
+            .into_iter()
+            .find(|(to, _)| *to == Scope::Master(0))
+            .expect("sent to the master");
+        let tx = DMRDPacket::parse(&data).unwrap();
+        assert_eq!(tx.dst, 2350);
+        assert_eq!(tx.rpt, 234500199);
+        assert_eq!(tx.src, 2345001);
+
+        // And in the other direction 2350 comes back to us as 91
+        let hbp = group(1234, 2350, 1, 0x2001);
+        let from = masters[0].peer.ip;
+        let actions = route(&rules, &hbp, from, &mut peers, &mut masters, &mut streams);
+        let sent = sent(&actions);
+        assert_eq!(sent.len(), 2);
+        for (to, data) in sent {
+            assert!(matches!(to, Scope::Peer(1) | Scope::Peer(2)));
+            assert_eq!(DMRDPacket::parse(&data).unwrap().dst, 91);
+        }
+    }
+
+    #[test]
+    fn egress_rules_only_count_peers_on_the_talkgroup() {
+        let (mut peers, mut streams) = (peers(), Streams::init(180, 5));
+        let mut rules = Rules::default();
+        rules.egress.deny_src = vec![IdRange {
+            from: 2345001,
+            to: 2345001,
+        }];
+        let hbp = group(1, 91, 1, 0x1001);
+        let actions = route(&rules, &hbp, addr(1), &mut peers, &mut [], &mut streams);
+        let dropped: Vec<&Action> = actions
+            .iter()
+            .filter(|a| matches!(a, Action::Dropped(_)))
+            .collect();
+        // Repeater 3 doesn't carry 91 so was never going to get it
+        assert_eq!(
+            dropped,
+            vec![&Action::Dropped(Dropped::Egress(
+                Scope::Peer(2),
+                Block::Source(2345001)
+            ))]
+        );
+        assert!(sent(&actions).is_empty());
+    }
+
+    // A master's own filter only stops traffic to that master
+    #[test]
+    fn master_filter_applies_after_the_global_rules() {
+        let (rules, mut peers, mut streams) = (Rules::default(), peers(), Streams::init(180, 5));
+        let mut masters = [master()];
+        masters[0].egress.deny_tg = vec![IdRange { from: 91, to: 91 }];
+        let hbp = group(1, 91, 1, 0x1001);
+        let actions = route(
+            &rules,
+            &hbp,
+            addr(1),
+            &mut peers,
+            &mut masters,
+            &mut streams,
+        );
+        assert!(actions.contains(&Action::Dropped(Dropped::Egress(
+            Scope::Master(0),
+            Block::Talkgroup(91)
+        ))));
+        let sent = sent(&actions);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, Scope::Peer(2));
+    }
+}
@@ -0,0 +1,267 @@
+use crate::peers::IdRange;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+
+/* rules.rs
+    Subscriber and talkgroup rules applied to DMRD traffic.
+
+    Rules are set globally, per peer and per master, with separate
+    filters for traffic coming in (ingress) and going out (egress).
+    A stream has to pass the global filter and the filter for the peer
+    or master it is coming from or going to. Master filters live with
+    the master, see master::Master::check.
+*/
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    pub ingress: Filter,
+    pub egress: Filter,
+    pub peers: Vec<PeerRules>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PeerRules {
+    pub id: IdRange,
+    #[serde(default)]
+    pub ingress: Filter,
+    #[serde(default)]
+    pub egress: Filter,
+}
+
+/* Deny entries always win. When an allow list has entries
+anything not on it is blocked, an empty allow list allows all. */
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+    pub allow_src: Vec<IdRange>,
+    pub deny_src: Vec<IdRange>,
+    pub allow_tg: Vec<IdRange>,
+    pub deny_tg: Vec<IdRange>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+// Who the traffic is coming from or going to, masters by their index in the config
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    Master(usize),
+    Peer(u32),
+}
+
+// Why a stream was blocked
+#[derive(Debug, PartialEq)]
+pub enum Block {
+    Source(u32),
+    Talkgroup(u32),
+}
+
+impl Filter {
+    // Talkgroup rules only apply to group calls, a private call's destination is a radio ID.
+    pub fn check(&self, src: u32, dst: u32, group: bool) -> Result<(), Block> {
+        if !permit(&self.allow_src, &self.deny_src, src) {
+            return Err(Block::Source(src));
+        }
+        if group && !permit(&self.allow_tg, &self.deny_tg, dst) {
+            return Err(Block::Talkgroup(dst));
+        }
+        Ok(())
+    }
+}
+
+impl Rules {
+    pub fn check(
+        &self,
+        dir: Direction,
+        scope: Scope,
+        src: u32,
+        dst: u32,
+        group: bool,
+    ) -> Result<(), Block> {
+        let global = match dir {
+            Direction::Ingress => &self.ingress,
+            Direction::Egress => &self.egress,
+        };
+        global.check(src, dst, group)?;
+
+        match scope {
+            Scope::Master(_) => Ok(()),
+            Scope::Peer(id) => {
+                for r in self.peers.iter().filter(|r| r.id.contains(id)) {
+                    match dir {
+                        Direction::Ingress => r.ingress.check(src, dst, group)?,
+                        Direction::Egress => r.egress.check(src, dst, group)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Block::Source(id) => write!(f, "source radio ID {} is blocked", id),
+            Block::Talkgroup(tg) => write!(f, "talkgroup {} is blocked", tg),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scope::Master(i) => write!(f, "master {}", i),
+            Scope::Peer(id) => write!(f, "peer {}", id),
+        }
+    }
+}
+
+fn permit(allow: &[IdRange], deny: &[IdRange], id: u32) -> bool {
+    if deny.iter().any(|r| r.contains(id)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|r| r.contains(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(from: u32, to: u32) -> Vec<IdRange> {
+        vec![IdRange { from, to }]
+    }
+
+    #[test]
+    fn deny_beats_allow() {
+        let f = Filter {
+            allow_src: ids(2340000, 2349999),
+            deny_src: ids(2345001, 2345001),
+            allow_tg: ids(1, 999),
+            deny_tg: ids(91, 91),
+        };
+        assert_eq!(f.check(2345001, 235, true), Err(Block::Source(2345001)));
+        assert_eq!(f.check(2345002, 91, true), Err(Block::Talkgroup(91)));
+        assert_eq!(f.check(2345002, 235, true), Ok(()));
+    }
+
+    #[test]
+    fn empty_allow_list_allows_all() {
+        let f = Filter::default();
+        assert_eq!(f.check(1, 1, true), Ok(()));
+        assert_eq!(f.check(u32::MAX, u32::MAX, true), Ok(()));
+
+        let f = Filter {
+            allow_tg: ids(91, 91),
+            ..Default::default()
+        };
+        assert_eq!(f.check(2345001, 91, true), Ok(()));
+        assert_eq!(f.check(2345001, 92, true), Err(Block::Talkgroup(92)));
+    }
+
+    // A private call's destination is a radio ID, talkgroup rules don't apply
+    #[test]
+    fn talkgroup_rules_skip_private_calls() {
+        let f = Filter {
+            deny_tg: ids(2345002, 2345002),
+            deny_src: ids(2345009, 2345009),
+            ..Default::default()
+        };
+        assert_eq!(
+            f.check(2345001, 2345002, true),
+            Err(Block::Talkgroup(2345002))
+        );
+        assert_eq!(f.check(2345001, 2345002, false), Ok(()));
+        // Source rules still do
+        assert_eq!(
+            f.check(2345009, 2345002, false),
+            Err(Block::Source(2345009))
+        );
+    }
+
+    #[test]
+    fn global_and_peer_rules_both_apply() {
+        let rules = Rules {
+            ingress: Filter {
+                deny_tg: ids(9, 9),
+                ..Default::default()
+            },
+            egress: Filter::default(),
+            peers: vec![PeerRules {
+                id: IdRange {
+                    from: 234500100,
+                    to: 234500199,
+                },
+                ingress: Filter {
+                    allow_tg: ids(9, 91),
+                    ..Default::default()
+                },
+                egress: Filter {
+                    deny_src: ids(2345001, 2345001),
+                    ..Default::default()
+                },
+            }],
+        };
+        let peer = Scope::Peer(234500101);
+        let other = Scope::Peer(234500201);
+        // The peer's allow can't undo a global deny
+        assert_eq!(
+            rules.check(Direction::Ingress, peer, 2345001, 9, true),
+            Err(Block::Talkgroup(9))
+        );
+        // Peer rules only for peers in the range, and only in their direction
+        assert_eq!(
+            rules.check(Direction::Ingress, peer, 2345001, 92, true),
+            Err(Block::Talkgroup(92))
+        );
+        assert_eq!(
+            rules.check(Direction::Ingress, other, 2345001, 92, true),
+            Ok(())
+        );
+        assert_eq!(
+            rules.check(Direction::Egress, peer, 2345001, 91, true),
+            Err(Block::Source(2345001))
+        );
+        assert_eq!(
+            rules.check(Direction::Ingress, peer, 2345001, 91, true),
+            Ok(())
+        );
+    }
+
+    // Master filters are checked by the master, here only the global ones count
+    #[test]
+    fn master_scope_skips_peer_rules() {
+        let rules = Rules {
+            egress: Filter {
+                deny_tg: ids(9990, 9990),
+                ..Default::default()
+            },
+            peers: vec![PeerRules {
+                id: IdRange { from: 0, to: 9 },
+                ingress: Filter::default(),
+                egress: Filter {
+                    deny_tg: ids(91, 91),
+                    ..Default::default()
+                },
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            rules.check(Direction::Egress, Scope::Master(0), 2345001, 91, true),
+            Ok(())
+        );
+        assert_eq!(
+            rules.check(Direction::Egress, Scope::Peer(0), 2345001, 91, true),
+            Err(Block::Talkgroup(91))
+        );
+        assert_eq!(
+            rules.check(Direction::Egress, Scope::Master(0), 2345001, 9990, true),
+            Err(Block::Talkgroup(9990))
+        );
+    }
+}
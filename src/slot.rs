@@ -6,6 +6,8 @@ pub enum Slots {
 }
 
 pub struct Slot {
+    // Seconds a slot stays locked to a talkgroup after its last traffic
+    pub hang: u64,
     slot_1: u32,
     slot_2: u32,
     slot_1_time: SystemTime,
@@ -14,8 +16,10 @@ pub struct Slot {
 
 impl Slot {
     pub fn init() -> Self {
-        let t = SystemTime::now();
+        // Start free, rather than held by TG 0 for the hang time
+        let t = SystemTime::UNIX_EPOCH;
         Self {
+            hang: 5,
             slot_1: 0,
             slot_2: 0,
             slot_1_time: t,
@@ -35,6 +39,7 @@ impl Slot {
                 }
 
                 self.slot_1 = tg;
+                self.slot_1_time = t;
             }
             Slots::Two(tg) => {
                 if self.slot_2 == tg {
@@ -45,23 +50,50 @@ impl Slot {
                 }
 
                 self.slot_2 = tg;
+                self.slot_2_time = t;
             }
         }
         true
     }
 
+    // Drop a lock on the talkgroup straight away rather than waiting for the hang time
+    pub fn release(&mut self, slot: Slots) {
+        match slot {
+            Slots::One(tg) if self.slot_1 == tg => {
+                self.slot_1 = 0;
+                self.slot_1_time = SystemTime::UNIX_EPOCH;
+            }
+            Slots::Two(tg) if self.slot_2 == tg => {
+                self.slot_2 = 0;
+                self.slot_2_time = SystemTime::UNIX_EPOCH;
+            }
+            _ => {}
+        }
+    }
+
+    // The talkgroup holding a slot and when it last had traffic, if it's still locked
+    pub fn locked(&self, slot: u8) -> Option<(u32, SystemTime)> {
+        let (tg, t) = match slot {
+            1 => (self.slot_1, self.slot_1_time),
+            2 => (self.slot_2, self.slot_2_time),
+            _ => return None,
+        };
+        let held = t.elapsed().map_or(true, |e| e.as_secs() <= self.hang);
+        (tg != 0 && held).then_some((tg, t))
+    }
+
     fn unlock(&mut self, slot: Slots) -> bool {
         match slot {
             Slots::One(_) => {
                 if let Ok(elp) = self.slot_1_time.elapsed() {
-                    if elp.as_secs() > 5 {
+                    if elp.as_secs() > self.hang {
                         return true;
                     }
                 }
             }
             Slots::Two(_) => {
                 if let Ok(elp) = self.slot_2_time.elapsed() {
-                    if elp.as_secs() > 5 {
+                    if elp.as_secs() > self.hang {
                         return true;
                     }
                 }
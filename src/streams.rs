@@ -1,56 +1,111 @@
+use crate::{alias::Alias, hb::DMRDPacket, rules::Scope};
 use std::{collections::hash_map::HashMap, time::SystemTime};
 
 /* streams.rs
     Store DMR stream ID data, this can be used for timeouts and stats.
 */
 
-/* Store stream data, maybe we also store talk group info in future */
+/* Store stream data along with who sent it and where it's going */
 
 pub struct Stream {
     pub id: u32,
+    // The repeater that sent it to us
+    pub peer: u32,
+    pub src: u32,
+    pub dst: u32,
+    pub slot: u8,
+    pub call_type: u8,
+    // Frames we got, and the gaps in the sequence numbers between them
+    pub frames: u32,
+    pub lost: u32,
+    seq: u8,
+    // Everywhere we've sent it, talker alias blocks follow the same path
+    pub receivers: Vec<Scope>,
+    pub alias: Alias,
+    pub blocked: bool,
+    pub blocked_peers: Vec<u32>,
     pub end_time: SystemTime,
     pub start_time: SystemTime,
     pub time_out: bool,
+    // Seen the voice terminator, the call is over without waiting out the hang time
+    pub terminated: bool,
 }
 
 pub struct Streams {
     pub current_streams: HashMap<u32, Stream>,
     pub total: usize,
+    // Seconds before a stream is cut off, and before a quiet stream is forgotten
+    timeout: u64,
+    hang: u64,
 }
 
 impl Stream {
-    fn start(id: u32) -> Self {
+    fn start(hbp: &DMRDPacket) -> Self {
         Self {
-            id,
+            id: hbp.si,
+            peer: hbp.rpt,
+            src: hbp.src,
+            dst: hbp.dst,
+            slot: hbp.sl,
+            call_type: hbp.ct,
+            frames: 1,
+            lost: 0,
+            seq: hbp.seq,
+            receivers: Vec::new(),
+            alias: Alias::default(),
+            blocked: false,
+            blocked_peers: Vec::new(),
             end_time: SystemTime::now(),
             start_time: SystemTime::now(),
             time_out: false,
+            terminated: hbp.is_terminator(),
         }
     }
 
     fn update_end(&mut self) {
         self.end_time = SystemTime::now();
     }
+
+    /* Count a frame, a jump forward in the sequence number means frames went
+    missing. Anything else is a repeat or out of order and isn't counted as lost. */
+    fn frame(&mut self, seq: u8) {
+        let gap = seq.wrapping_sub(self.seq.wrapping_add(1));
+        if gap < 128 {
+            self.lost += gap as u32;
+            self.seq = seq;
+        }
+        self.frames += 1;
+    }
 }
 
 impl Streams {
-    pub fn init() -> Self {
+    pub fn init(timeout: u64, hang: u64) -> Self {
         Self {
             current_streams: HashMap::new(),
             total: 0,
+            timeout,
+            hang,
         }
     }
 
-    /* Add a stream via ID, if already exists check to see if timed out */
-    pub fn stream(&mut self, id: u32) -> bool {
-        if let Some(v) = self.current_streams.get_mut(&id) {
+    // New timeouts from a config reload
+    pub fn timers(&mut self, timeout: u64, hang: u64) {
+        self.timeout = timeout;
+        self.hang = hang;
+    }
+
+    /* Add a stream by its ID, if already exists check to see if timed out */
+    pub fn stream(&mut self, hbp: &DMRDPacket) -> bool {
+        if let Some(v) = self.current_streams.get_mut(&hbp.si) {
             match v.start_time.elapsed() {
                 Ok(t) => {
-                    if t.as_secs() >= 300 {
+                    if t.as_secs() >= self.timeout {
                         v.time_out = true;
                         return true;
                     }
+                    v.frame(hbp.seq);
                     v.update_end();
+                    v.terminated |= hbp.is_terminator();
                     return false;
                 }
                 Err(_) => return false,
@@ -58,22 +113,90 @@ impl Streams {
         }
 
         self.total += 1;
-        self.current_streams.insert(id, Stream::start(id));
+        self.current_streams.insert(hbp.si, Stream::start(hbp));
         false
     }
 
-    /* Check if we have any redundant streams */
-    pub fn check(&mut self) {
+    /* Record that a stream was blocked coming in, returns true the first time
+    so the caller only logs it once */
+    pub fn block(&mut self, id: u32) -> bool {
+        match self.current_streams.get_mut(&id) {
+            Some(s) if !s.blocked => {
+                s.blocked = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /* As above but for a stream blocked going out to a peer */
+    pub fn block_peer(&mut self, id: u32, peer: u32) -> bool {
+        match self.current_streams.get_mut(&id) {
+            Some(s) if !s.blocked_peers.contains(&peer) => {
+                s.blocked_peers.push(peer);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_blocked(&self, id: u32) -> bool {
+        match self.current_streams.get(&id) {
+            Some(s) => s.blocked,
+            None => false,
+        }
+    }
+
+    /* The stream a talker alias belongs to, the newest one from that
+    radio via that repeater */
+    pub fn find(&mut self, peer: u32, src: u32) -> Option<&mut Stream> {
         self.current_streams
-            .retain(|_, v| match v.end_time.elapsed() {
-                Ok(e) => {
-                    if e.as_secs() >= 5 {
-                        return false;
-                    } else {
-                        return true;
-                    }
-                }
-                Err(_) => false,
+            .values_mut()
+            .filter(|s| s.peer == peer && s.src == src && !s.time_out)
+            .max_by_key(|s| s.start_time)
+    }
+
+    /* Remember we sent a stream somewhere */
+    pub fn sent(&mut self, id: u32, to: Scope) {
+        if let Some(s) = self.current_streams.get_mut(&id) {
+            if !s.receivers.contains(&to) {
+                s.receivers.push(to);
+            }
+        }
+    }
+
+    /* A peer has gone, forget its streams and hand them back so the
+    caller can free up the slots they were holding */
+    pub fn end_peer(&mut self, peer: u32) -> Vec<Stream> {
+        let ids: Vec<u32> = self
+            .current_streams
+            .values()
+            .filter(|s| s.peer == peer)
+            .map(|s| s.id)
+            .collect();
+        ids.iter()
+            .filter_map(|id| self.current_streams.remove(id))
+            .collect()
+    }
+
+    /* Check if we have any redundant streams, hands back the ones that have
+    ended. A terminated stream goes on the next check, a frame arriving after
+    that starts a new stream. */
+    pub fn check(&mut self) -> Vec<Stream> {
+        let hang = self.hang;
+        let ended: Vec<u32> = self
+            .current_streams
+            .values()
+            .filter(|v| match v.end_time.elapsed() {
+                _ if v.terminated => true,
+                Ok(e) => e.as_secs() >= hang,
+                Err(_) => true,
             })
+            .map(|v| v.id)
+            .collect();
+        ended
+            .iter()
+            .filter_map(|id| self.current_streams.remove(id))
+            .collect()
     }
 }
@@ -1,40 +1,463 @@
+use crate::{
+    api::ApiConfig, control::ControlConfig, directory::DirectoryConfig, hb, logger::LogConfig,
+    peers, rules,
+};
 use serde_derive::{Deserialize, Serialize};
-use std::{fs, str, time};
+use std::{fmt, fs, net::SocketAddr, time};
 
 /* system.rs
     Data related to the DMRPal System.
 */
 
+/* Everything in dmrpal.toml. Any key left out takes the default,
+see Config::default for the values. */
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    pub my_id: u32,
-    pub master_ip: String,
+    pub bind: String,
+    // The old dprint! levels, only used when [log] has no level
     pub verbose: u8,
+    pub disconnect_tg: u32,
+    pub echo_tg: u32,
+    pub echo_slot: u8,
+    pub masters: Vec<MasterConfig>,
+    pub acl: peers::Acl,
+    pub auth: Auth,
+    pub info: Info,
+    pub peers: PeerDefaults,
+    pub rules: rules::Rules,
+    pub timers: Timers,
+    pub database: Database,
+    pub directory: DirectoryConfig,
+    pub api: ApiConfig,
+    pub log: LogConfig,
+    pub control: ControlConfig,
 }
 
+// Where to keep state between restarts, off unless a path is given
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Database {
+    pub path: String,
+    // Most last-heard records to keep
+    pub heard: usize,
+}
+
+// A static talkgroup on a timeslot
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StaticTg {
+    pub id: u32,
+    pub slot: u8,
+}
+
+/* An upstream master we log in to. `id` is our ID on that network, the
+same ID can be used on several masters. */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MasterConfig {
+    pub name: String,
+    pub enabled: bool,
+    pub id: u32,
+    pub address: String,
+    pub password: String,
+    pub callsign: String,
+    pub options: String,
+    pub talkgroups: Vec<MasterTg>,
+    pub ingress: rules::Filter,
+    pub egress: rules::Filter,
+}
+
+/* A talkgroup shared with a master. If the master knows it by another
+number set `remote` and it is rewritten in both directions. */
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MasterTg {
+    pub id: u32,
+    pub slot: u8,
+    pub remote: Option<u32>,
+}
+
+// Applied to every repeater that logs in
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerDefaults {
+    pub talkgroups: Vec<StaticTg>,
+    // Minutes before a user activated talkgroup is dropped
+    pub ua_expire: u64,
+}
+
+// All in seconds
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timers {
+    pub stats: u64,
+    pub peer_timeout: u64,
+    pub stream_timeout: u64,
+    pub stream_hang: u64,
+    pub slot_hang: u64,
+    pub echo_delay: u64,
+    pub master_ping: u64,
+    pub master_timeout: u64,
+    // How long to wait for the master to answer each login step
+    pub master_step: u64,
+    // Shortest and longest wait before logging in again, doubled on every failure
+    pub master_retry_min: u64,
+    pub master_retry: u64,
+    // Longest we'll take to say goodbye when asked to stop
+    pub shutdown: u64,
+    // Ask connected repeaters for a beacon this often, 0 to only do it when a master asks
+    pub beacon: u64,
+}
+
+// The old single master settings, an unknown field error alone wouldn't say where they went
+const MOVED: [&str; 6] = [
+    "my_id",
+    "master_ip",
+    "master_password",
+    "master_callsign",
+    "master_options",
+    "master_talkgroups",
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Invalid(String),
+}
+
+/* Passphrases for repeaters logging in to us. Peers listed in
+`peers` use their own password, everyone else uses `password`. There
+is no default, a config has to set one. */
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    pub password: String,
+    pub peers: Vec<PeerPassword>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PeerPassword {
+    pub id: u32,
+    pub password: String,
+}
+
+/* The details we send to a master in our RPTC packet. Anything too
+long for its field is cut short when the packet is built. */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Info {
+    pub callsign: String,
+    pub rx_freq: u32,
+    pub tx_freq: u32,
+    pub power: u8,
+    pub color_code: u8,
+    pub latitude: f32,
+    pub longitude: f32,
+    pub height: u16,
+    pub location: String,
+    pub description: String,
+    pub url: String,
+    pub slots: u8,
+}
+
+// Counters kept across restarts when there is a database
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct System {
+    pub acl_denied: usize,
     pub master_reconnects: usize,
+    pub master_failures: usize,
+    pub master_naks: usize,
+    pub master_closes: usize,
+    pub peer_closes: usize,
+    pub streams_blocked: usize,
     pub total_timeouts: usize,
+    #[serde(skip, default = "time::SystemTime::now")]
     pub uptime: time::SystemTime,
 }
 
 impl Config {
-    pub fn load() -> Self {
-        if let Ok(file) = fs::read("dmrpal.toml") {
-            let file_data = str::from_utf8(&file).unwrap();
-            toml::from_str(file_data).unwrap()
-        } else {
-            panic!("Configuration file not found!");
+    pub fn load(path: &str) -> Result<Self, ConfigError> {
+        let file = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+        if let Ok(toml::Value::Table(t)) = file.parse::<toml::Value>() {
+            if let Some(k) = MOVED.iter().find(|k| t.contains_key(**k)) {
+                return Err(ConfigError::Invalid(format!(
+                    "{} has been replaced by [[masters]], see src/dmrpal.toml",
+                    k
+                )));
+            }
+        }
+        let config: Self =
+            toml::from_str(&file).map_err(|e| ConfigError::Parse(path.to_owned(), e))?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /* Take the settings that can change while running. Sockets, the
+    database and masters are only set up at start so keep what we have. */
+    pub fn reload(&mut self, mut new: Config) {
+        new.bind = std::mem::take(&mut self.bind);
+        new.masters = std::mem::take(&mut self.masters);
+        new.database = std::mem::take(&mut self.database);
+        new.api = std::mem::take(&mut self.api);
+        new.control = std::mem::take(&mut self.control);
+        *self = new;
+    }
+
+    // Catch the mistakes serde can't
+    fn check(&self) -> Result<(), ConfigError> {
+        if self.bind.parse::<SocketAddr>().is_err() {
+            return Err(ConfigError::Invalid(format!(
+                "bind address \"{}\" is not an IP:port",
+                self.bind
+            )));
+        }
+        if !self.api.bind.is_empty() && self.api.bind.parse::<SocketAddr>().is_err() {
+            return Err(ConfigError::Invalid(format!(
+                "api bind address \"{}\" is not an IP:port",
+                self.api.bind
+            )));
+        }
+        for (i, m) in self.masters.iter().enumerate() {
+            // Looked up when we log in, so a master doesn't have to resolve to start
+            let port = m.address.rsplit_once(':').map(|(_, p)| p.parse::<u16>());
+            if !matches!(port, Some(Ok(_))) {
+                return Err(ConfigError::Invalid(format!(
+                    "master {} address \"{}\" is not a host:port",
+                    m.name, m.address
+                )));
+            }
+            if m.options.len() > hb::RPTO_MAX - hb::RPTO_MIN {
+                return Err(ConfigError::Invalid(format!(
+                    "master {} options are {} bytes, the most RPTO can carry is {}",
+                    m.name,
+                    m.options.len(),
+                    hb::RPTO_MAX - hb::RPTO_MIN
+                )));
+            }
+            if m.id == 0 {
+                return Err(ConfigError::Invalid(format!("master {} has no id", m.name)));
+            }
+            if m.password.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "master {} has no password",
+                    m.name
+                )));
+            }
+            if self.masters[..i].iter().any(|o| o.name == m.name) {
+                return Err(ConfigError::Invalid(format!(
+                    "master name {} is used more than once",
+                    m.name
+                )));
+            }
+            if let Some(tg) = m.talkgroups.iter().find(|t| !(1..=2).contains(&t.slot)) {
+                return Err(ConfigError::Invalid(format!(
+                    "master {} talkgroup {} has slot {}, it must be 1 or 2",
+                    m.name, tg.id, tg.slot
+                )));
+            }
+        }
+        if self.auth.password.is_empty() {
+            return Err(ConfigError::Invalid("auth password is not set".to_owned()));
+        }
+        if let Some(p) = self.auth.peers.iter().find(|p| p.password.is_empty()) {
+            return Err(ConfigError::Invalid(format!(
+                "peer {} has an empty password",
+                p.id
+            )));
+        }
+        if self.timers.master_retry_min == 0
+            || self.timers.master_retry_min > self.timers.master_retry
+        {
+            return Err(ConfigError::Invalid(format!(
+                "master_retry_min {} must be between 1 and master_retry {}",
+                self.timers.master_retry_min, self.timers.master_retry
+            )));
+        }
+        if !(1..=2).contains(&self.echo_slot) {
+            return Err(ConfigError::Invalid(format!(
+                "echo_slot must be 1 or 2, not {}",
+                self.echo_slot
+            )));
+        }
+        for tg in &self.peers.talkgroups {
+            if !(1..=2).contains(&tg.slot) {
+                return Err(ConfigError::Invalid(format!(
+                    "talkgroup {} has slot {}, it must be 1 or 2",
+                    tg.id, tg.slot
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:55555".to_owned(),
+            verbose: 4,
+            disconnect_tg: 4000,
+            echo_tg: 9990,
+            echo_slot: 2,
+            masters: Vec::new(),
+            acl: peers::Acl::default(),
+            auth: Auth::default(),
+            info: Info::default(),
+            peers: PeerDefaults::default(),
+            rules: rules::Rules::default(),
+            timers: Timers::default(),
+            database: Database::default(),
+            directory: DirectoryConfig::default(),
+            api: ApiConfig::default(),
+            log: LogConfig::default(),
+            control: ControlConfig::default(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "unable to read {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "error in {}: {}", path, e),
+            ConfigError::Invalid(e) => write!(f, "invalid configuration: {}", e),
+        }
+    }
+}
+
+impl Default for MasterConfig {
+    fn default() -> Self {
+        Self {
+            name: String::default(),
+            enabled: true,
+            id: 0,
+            address: String::default(),
+            password: String::default(),
+            callsign: String::default(),
+            options: String::default(),
+            talkgroups: Vec::new(),
+            ingress: rules::Filter::default(),
+            egress: rules::Filter::default(),
+        }
+    }
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            heard: 1000,
+        }
+    }
+}
+
+impl Default for PeerDefaults {
+    fn default() -> Self {
+        Self {
+            talkgroups: Vec::new(),
+            ua_expire: 15,
+        }
+    }
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self {
+            stats: 60,
+            peer_timeout: 15,
+            stream_timeout: 300,
+            stream_hang: 5,
+            slot_hang: 5,
+            echo_delay: 5,
+            master_ping: 15,
+            master_timeout: 30,
+            master_step: 5,
+            master_retry_min: 5,
+            master_retry: 300,
+            shutdown: 5,
+            beacon: 0,
+        }
+    }
+}
+
+impl Auth {
+    pub fn password(&self, id: u32) -> &str {
+        match self.peers.iter().find(|p| p.id == id) {
+            Some(p) => &p.password,
+            None => &self.password,
+        }
+    }
+}
+
+impl Default for Info {
+    fn default() -> Self {
+        Self {
+            callsign: String::default(),
+            rx_freq: 0,
+            tx_freq: 0,
+            power: 0,
+            color_code: 1,
+            latitude: 0.0,
+            longitude: 0.0,
+            height: 0,
+            location: String::default(),
+            description: String::default(),
+            url: String::default(),
+            slots: 3,
         }
     }
 }
 
+impl Default for System {
+    fn default() -> Self {
+        Self::init()
+    }
+}
+
 impl System {
     pub fn init() -> Self {
         Self {
+            acl_denied: 0,
             master_reconnects: 0,
+            master_failures: 0,
+            master_naks: 0,
+            master_closes: 0,
+            peer_closes: 0,
+            streams_blocked: 0,
             total_timeouts: 0,
             uptime: time::SystemTime::now(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The sample has every option, it must keep up with the structs
+    #[test]
+    fn sample_config_loads() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/dmrpal.toml");
+        if let Err(e) = Config::load(path) {
+            panic!("{}", e);
+        }
+    }
+
+    #[test]
+    fn passwords_are_required() {
+        let ok = |config: &str| toml::from_str::<Config>(config).unwrap().check().is_ok();
+        assert!(!ok(""));
+        assert!(ok("[auth]\npassword = \"x\""));
+        assert!(!ok(
+            "[auth]\npassword = \"x\"\n[[auth.peers]]\nid = 1\npassword = \"\""
+        ));
+        assert!(!ok(
+            "[auth]\npassword = \"x\"\n[[masters]]\nname = \"m\"\nid = 1\naddress = \"m:1\""
+        ));
+        assert!(ok("[auth]\npassword = \"x\"\n[[masters]]\nname = \"m\"\nid = 1\naddress = \"m:1\"\npassword = \"y\""));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::peers::Peertype;
+use crate::{peers::Peertype, warn};
 use std::time::SystemTime;
 
 pub enum TgActivate {
@@ -17,9 +17,9 @@ pub struct Talkgroup {
     pub time_stamp: SystemTime,
 }
 
-impl Talkgroup {
-    // return a default value for talkgroup
-    pub fn default() -> Self {
+// return a default value for talkgroup
+impl Default for Talkgroup {
+    fn default() -> Self {
         Self {
             expire: 0,
             id: 0,
@@ -30,27 +30,28 @@ impl Talkgroup {
             time_stamp: SystemTime::now(),
         }
     }
+}
 
-    // Remove a talkgroup from a peer
-    pub fn ua_clear(&mut self) -> bool {
+impl Talkgroup {
+    // Remove a talkgroup from a peer, unless it has had traffic in the last `hang` seconds
+    pub fn ua_clear(&mut self, hang: u64) -> bool {
         if self.ua {
             return match self.time_stamp.elapsed() {
                 Ok(ts) => {
                     if ts.as_secs() > self.expire * 60 {
                         // If the talkgroup has traffic, skip and try again when there's no traffic
                         if let Ok(la) = self.la.elapsed() {
-                            if la.as_secs() <= 5 {
+                            if la.as_secs() <= hang {
                                 return true;
                             }
                         };
-                        println!("Removing TG: {}, From Peer: {}", self.id, self.id);
                         false
                     } else {
                         true
                     }
                 }
                 Err(_) => {
-                    println!("There was an error passing time for UA, removing TG!");
+                    warn!(tg = self.id; "Error reading UA time, removing talkgroup");
                     false
                 }
             };
@@ -63,10 +64,7 @@ impl Talkgroup {
         let (ua, talk_group, expire) = match tg {
             TgActivate::Static(u) => (false, u, 0),
             TgActivate::Ua(u) => {
-                let e: u64 = match exp {
-                    Some(v) => v,
-                    None => 900,
-                };
+                let e: u64 = exp.unwrap_or(900);
                 (true, u, e)
             }
         };
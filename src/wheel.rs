@@ -0,0 +1,134 @@
+use std::time::{Duration, Instant};
+
+/* wheel.rs
+    A hashed timer wheel. Timers are dropped into the slot for the tick
+    they fall due on, so scheduling is cheap and expiring only looks at
+    the slots the clock has moved past. Timers more than one turn of the
+    wheel away just stay in their slot until their tick comes round.
+*/
+
+pub struct Wheel<T> {
+    start: Instant,
+    resolution: Duration,
+    slots: Vec<Vec<(u64, T)>>,
+    // The next tick we haven't expired yet
+    current: u64,
+}
+
+impl<T> Wheel<T> {
+    pub fn new(resolution: Duration, slots: usize) -> Self {
+        Self {
+            start: Instant::now(),
+            resolution,
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            current: 0,
+        }
+    }
+
+    // Fire `item` no sooner than `after` from now
+    pub fn schedule(&mut self, after: Duration, item: T) {
+        let at = Instant::now() + after - self.start;
+        // Round up so a timer never fires early
+        let due = (at.as_nanos().div_ceil(self.resolution.as_nanos()) as u64).max(self.current);
+        let len = self.slots.len() as u64;
+        self.slots[(due % len) as usize].push((due, item));
+    }
+
+    // Drop every timer `f` picks out before it fires
+    pub fn cancel(&mut self, f: impl Fn(&T) -> bool) {
+        for slot in &mut self.slots {
+            slot.retain(|(_, item)| !f(item));
+        }
+    }
+
+    // Take every timer that has fallen due by `now`
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let mut fired = Vec::new();
+        let target = (now.saturating_duration_since(self.start).as_nanos()
+            / self.resolution.as_nanos()) as u64;
+        if target < self.current {
+            return fired;
+        }
+        let len = self.slots.len() as u64;
+        // After a long gap every slot is visited once rather than looping round
+        for tick in self.current..self.current + (target - self.current + 1).min(len) {
+            let slot = &mut self.slots[(tick % len) as usize];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].0 <= target {
+                    fired.push(slot.swap_remove(i).1);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.current = target + 1;
+        fired
+    }
+
+    // When the earliest timer falls due
+    pub fn next(&self) -> Option<Instant> {
+        self.slots
+            .iter()
+            .flatten()
+            .map(|(due, _)| *due)
+            .min()
+            .map(|due| self.start + Duration::from_nanos(self.resolution.as_nanos() as u64 * due))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(wheel: &Wheel<u32>, ms: u64) -> Instant {
+        wheel.start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn fires_when_due_and_not_before() {
+        let mut wheel = Wheel::new(Duration::from_millis(10), 256);
+        wheel.schedule(Duration::from_millis(50), 1);
+        wheel.schedule(Duration::from_millis(20), 2);
+        assert!(wheel.expire(at(&wheel, 15)).is_empty());
+        assert_eq!(wheel.expire(at(&wheel, 45)), vec![2]);
+        assert_eq!(wheel.expire(at(&wheel, 65)), vec![1]);
+        assert!(wheel.expire(at(&wheel, 1000)).is_empty());
+    }
+
+    #[test]
+    fn cancelled_timers_never_fire() {
+        let mut wheel = Wheel::new(Duration::from_millis(10), 256);
+        wheel.schedule(Duration::from_millis(20), 1);
+        wheel.schedule(Duration::from_millis(20), 2);
+        wheel.schedule(Duration::from_millis(5000), 1);
+        wheel.cancel(|t| *t == 1);
+        assert_eq!(wheel.expire(at(&wheel, 10_000)), vec![2]);
+        assert_eq!(wheel.next(), None);
+    }
+
+    #[test]
+    fn waits_a_turn_past_the_last_slot() {
+        // 256 slots of 10ms go round every 2.56s
+        let mut wheel = Wheel::new(Duration::from_millis(10), 256);
+        wheel.schedule(Duration::from_millis(3000), 1);
+        wheel.schedule(Duration::from_millis(8000), 2);
+        assert!(wheel.expire(at(&wheel, 2600)).is_empty());
+        assert_eq!(wheel.expire(at(&wheel, 3100)), vec![1]);
+        assert!(wheel.expire(at(&wheel, 7900)).is_empty());
+        assert_eq!(wheel.expire(at(&wheel, 8100)), vec![2]);
+    }
+
+    #[test]
+    fn next_is_the_earliest_timer() {
+        let mut wheel = Wheel::new(Duration::from_millis(10), 256);
+        assert_eq!(wheel.next(), None);
+        wheel.schedule(Duration::from_millis(4000), 1);
+        wheel.schedule(Duration::from_millis(300), 2);
+        let next = wheel.next().unwrap();
+        assert!(next >= at(&wheel, 300) && next <= at(&wheel, 320));
+        wheel.expire(next);
+        let next = wheel.next().unwrap();
+        assert!(next >= at(&wheel, 4000) && next <= at(&wheel, 4020));
+    }
+}